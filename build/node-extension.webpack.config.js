@@ -39,7 +39,11 @@ const config = {
   },
   plugins: [
     new CopyPlugin({
-      patterns: [{ from: 'node_modules/source-map/lib/mappings.wasm', to: './', }],
+      patterns: [
+        { from: 'node_modules/source-map/lib/mappings.wasm', to: './', },
+        { from: 'src/extensionHostTests.js', to: './', },
+        { from: 'src/testBootstrap.js', to: './', },
+      ],
     }),
   ],
 };
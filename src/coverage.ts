@@ -0,0 +1,259 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import { promises as fs } from 'fs';
+import * as path from 'path';
+import { SourceMapConsumer } from 'source-map';
+import * as vscode from 'vscode';
+import { readInlineSourceMap } from './testOutputScanner';
+
+/**
+ * Reports the repo's test scripts write with `--coverage`. The second is
+ * written instead of the first when only some files are run.
+ */
+const LCOV_REPORTS = ['.build/coverage/lcov.info', '.build/coverage-single/lcov.info'];
+
+/** Number of times each line ran, by zero-based line number */
+export type LineHits = Map<number, number>;
+
+export interface IFileCoverage {
+  uri: vscode.Uri;
+  lines: LineHits;
+}
+
+/** Coverage of source files, keyed by their URI */
+export type CoverageMap = Map<string, IFileCoverage>;
+
+export interface ICoverageSummary {
+  /** Lines that ran at least once */
+  covered: number;
+  /** Lines that could have run */
+  total: number;
+}
+
+const toKey = (uri: vscode.Uri) => uri.toString().toLowerCase();
+
+const addHits = (coverage: CoverageMap, uri: vscode.Uri, line: number, hits: number) => {
+  const key = toKey(uri);
+  let file = coverage.get(key);
+  if (!file) {
+    file = { uri, lines: new Map() };
+    coverage.set(key, file);
+  }
+
+  file.lines.set(line, (file.lines.get(line) ?? 0) + hits);
+};
+
+/**
+ * Adds the hits of each line in the coverage to the target.
+ */
+export const mergeCoverage = (target: CoverageMap, coverage: CoverageMap) => {
+  for (const { uri, lines } of coverage.values()) {
+    for (const [line, hits] of lines) {
+      addHits(target, uri, line, hits);
+    }
+  }
+};
+
+/**
+ * Gets how many lines in the coverage ran.
+ */
+export const summarizeCoverage = (files: Iterable<IFileCoverage>): ICoverageSummary => {
+  const summary = { covered: 0, total: 0 };
+  for (const { lines } of files) {
+    for (const hits of lines.values()) {
+      summary.total++;
+      summary.covered += hits > 0 ? 1 : 0;
+    }
+  }
+
+  return summary;
+};
+
+export const formatCoverage = ({ covered, total }: ICoverageSummary) =>
+  `${total ? Math.floor((covered / total) * 1000) / 10 : 100}%`;
+
+/**
+ * Parses an lcov report into the hits of each line, by the path of the file.
+ */
+const parseLcov = (report: string, repo: string) => {
+  const files = new Map<string, [line: number, hits: number][]>();
+  let current: [line: number, hits: number][] | undefined;
+  for (const line of report.split(/\r?\n/)) {
+    if (line.startsWith('SF:')) {
+      const file = path.resolve(repo, line.slice(3));
+      current = files.get(file) ?? [];
+      files.set(file, current);
+    } else if (line.startsWith('DA:') && current) {
+      const [lineNumber, hits] = line.slice(3).split(',').map(Number);
+      current.push([lineNumber - 1, hits]);
+    } else if (line === 'end_of_record') {
+      current = undefined;
+    }
+  }
+
+  return files;
+};
+
+/**
+ * Adds coverage of a compiled file to the coverage of its sources, mapped
+ * through the file's inline source map. Lines that don't map are dropped.
+ */
+const addCompiledCoverage = async (
+  coverage: CoverageMap,
+  file: string,
+  lines: ReadonlyArray<[line: number, hits: number]>
+) => {
+  const fileUri = vscode.Uri.file(file).toString();
+  let sourceMap: SourceMapConsumer | undefined;
+  try {
+    sourceMap = await readInlineSourceMap(fileUri);
+  } catch (e) {
+    console.warn(`Error parsing sourcemap for ${fileUri}: ${e.stack}`);
+  }
+
+  if (!sourceMap) {
+    return;
+  }
+
+  for (const [line, hits] of lines) {
+    const position = sourceMap.originalPositionFor({
+      line: line + 1,
+      column: 0,
+      bias: SourceMapConsumer.LEAST_UPPER_BOUND,
+    });
+    if (position.source !== null && position.line !== null) {
+      addHits(coverage, vscode.Uri.parse(position.source), position.line - 1, hits);
+    }
+  }
+
+  sourceMap.destroy();
+};
+
+/**
+ * Reads line coverage written by a test process that started at the given
+ * time, mapped back to the TypeScript sources. It's empty if the process
+ * didn't write any.
+ */
+export async function readCoverage(folder: vscode.WorkspaceFolder, since: number) {
+  const repo = folder.uri.fsPath;
+  const coverage: CoverageMap = new Map();
+  for (const report of LCOV_REPORTS) {
+    const file = path.join(repo, report);
+    let contents: string;
+    try {
+      if ((await fs.stat(file)).mtimeMs < since) {
+        continue;
+      }
+      contents = await fs.readFile(file, 'utf-8');
+    } catch {
+      continue;
+    }
+
+    for (const [source, lines] of parseLcov(contents, repo)) {
+      if (source.endsWith('.js')) {
+        await addCompiledCoverage(coverage, source, lines);
+      } else {
+        for (const [line, hits] of lines) {
+          addHits(coverage, vscode.Uri.file(source), line, hits);
+        }
+      }
+    }
+  }
+
+  return coverage;
+}
+
+/**
+ * Holds the coverage of the last run with coverage, and shows which lines
+ * ran in editors.
+ */
+export class TestCoverage implements vscode.Disposable {
+  private files: CoverageMap = new Map();
+  private readonly onDidChangeEmitter = new vscode.EventEmitter<void>();
+  private readonly coveredDecoration = vscode.window.createTextEditorDecorationType({
+    isWholeLine: true,
+    backgroundColor: new vscode.ThemeColor('diffEditor.insertedTextBackground'),
+    overviewRulerColor: new vscode.ThemeColor('testing.iconPassed'),
+    overviewRulerLane: vscode.OverviewRulerLane.Left,
+  });
+  private readonly uncoveredDecoration = vscode.window.createTextEditorDecorationType({
+    isWholeLine: true,
+    backgroundColor: new vscode.ThemeColor('diffEditor.removedTextBackground'),
+    overviewRulerColor: new vscode.ThemeColor('testing.iconFailed'),
+    overviewRulerLane: vscode.OverviewRulerLane.Left,
+  });
+  private readonly listener = vscode.window.onDidChangeVisibleTextEditors(editors =>
+    editors.forEach(e => this.decorate(e))
+  );
+  // lines of an edited file no longer match those the coverage was collected for
+  private readonly documentListener = vscode.workspace.onDidChangeTextDocument(e => {
+    if (e.contentChanges.length && this.files.delete(toKey(e.document.uri))) {
+      vscode.window.visibleTextEditors
+        .filter(editor => editor.document === e.document)
+        .forEach(editor => this.decorate(editor));
+      this.onDidChangeEmitter.fire();
+    }
+  });
+
+  /**
+   * Fired when coverage is shown or cleared, including when a file's
+   * coverage is cleared because it was edited.
+   */
+  public readonly onDidChange = this.onDidChangeEmitter.event;
+
+  /**
+   * Gets whether there's any coverage shown.
+   */
+  public get isEmpty() {
+    return !this.files.size;
+  }
+
+  /**
+   * Shows the coverage, replacing any from earlier runs.
+   */
+  public set(files: CoverageMap) {
+    this.files = new Map(files);
+    vscode.window.visibleTextEditors.forEach(e => this.decorate(e));
+    this.onDidChangeEmitter.fire();
+  }
+
+  public clear() {
+    this.set(new Map());
+  }
+
+  /**
+   * Summarizes coverage of the files that match the filter, or returns
+   * undefined if none of them have coverage.
+   */
+  public summarize(filter: (uri: vscode.Uri) => boolean) {
+    const files = [...this.files.values()].filter(f => filter(f.uri));
+    return files.length ? summarizeCoverage(files) : undefined;
+  }
+
+  /**
+   * @override
+   */
+  public dispose() {
+    this.listener.dispose();
+    this.documentListener.dispose();
+    this.coveredDecoration.dispose();
+    this.uncoveredDecoration.dispose();
+    this.onDidChangeEmitter.dispose();
+  }
+
+  private decorate(editor: vscode.TextEditor) {
+    const file = this.files.get(toKey(editor.document.uri));
+    const covered: vscode.Range[] = [];
+    const uncovered: vscode.Range[] = [];
+    for (const [line, hits] of file?.lines ?? []) {
+      if (line < editor.document.lineCount) {
+        (hits > 0 ? covered : uncovered).push(new vscode.Range(line, 0, line, 0));
+      }
+    }
+
+    editor.setDecorations(this.coveredDecoration, covered);
+    editor.setDecorations(this.uncoveredDecoration, uncovered);
+  }
+}
@@ -0,0 +1,104 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import { constants, promises as fs } from 'fs';
+import * as path from 'path';
+import * as vscode from 'vscode';
+import { getModifiedTime } from './modifiedTime';
+import { runShellTask } from './tasks';
+
+/** Command that downloads the Electron version the repo expects */
+const DOWNLOAD_COMMAND = 'yarn electron';
+
+/**
+ * Modified times of binaries that passed the checks, so they're only checked
+ * again once the binary is rebuilt or downloaded.
+ */
+const verified = new Map<string, number>();
+
+/** Whether the user is already being asked to download Electron */
+let isPrompting = false;
+
+const readFileOrUndefined = async (file: string) => {
+  try {
+    return await fs.readFile(file, 'utf-8');
+  } catch {
+    return undefined;
+  }
+};
+
+/**
+ * Gets the Electron version the repo expects, from the `target` in its `.npmrc`.
+ */
+const getExpectedVersion = async (repo: string) => {
+  const npmrc = await readFileOrUndefined(path.join(repo, '.npmrc'));
+  return npmrc && /^target\s*=\s*"?([^"\s]+)"?/m.exec(npmrc)?.[1];
+};
+
+/**
+ * Gets the version of the downloaded Electron, from the file next to it.
+ */
+const getDownloadedVersion = async (repo: string) => {
+  const version = await readFileOrUndefined(path.join(repo, '.build', 'electron', 'version'));
+  return version?.trim().replace(/^v/, '');
+};
+
+/**
+ * Gets the reason the Electron binary can't be used, if any.
+ */
+const getBinaryProblem = async (repo: string, binary: string) => {
+  try {
+    await fs.access(binary, process.platform === 'win32' ? constants.F_OK : constants.X_OK);
+  } catch (e) {
+    return e.code === 'ENOENT'
+      ? `Electron was not found at ${binary}.`
+      : `Electron at ${binary} can't be executed.`;
+  }
+
+  const [expected, downloaded] = await Promise.all([
+    getExpectedVersion(repo),
+    getDownloadedVersion(repo),
+  ]);
+  if (expected && downloaded && expected !== downloaded) {
+    return `Electron ${downloaded} is downloaded, but the repo expects ${expected}.`;
+  }
+
+  return undefined;
+};
+
+/**
+ * Checks that the Electron binary can run tests. If it can't, offers to
+ * download it and throws an error explaining why.
+ */
+export async function verifyElectronBinary(folder: vscode.WorkspaceFolder, binary: string) {
+  const modified = await getModifiedTime(binary);
+  if (modified !== undefined && verified.get(binary) === modified) {
+    return;
+  }
+
+  const problem = await getBinaryProblem(folder.uri.fsPath, binary);
+  if (!problem) {
+    if (modified !== undefined) {
+      verified.set(binary, modified);
+    }
+    return;
+  }
+
+  verified.delete(binary);
+
+  if (!isPrompting) {
+    isPrompting = true;
+    const download = 'Download Electron';
+    vscode.window
+      .showErrorMessage(`${problem} Run \`${DOWNLOAD_COMMAND}\` to download it.`, download)
+      .then(choice => {
+        isPrompting = false;
+        if (choice === download) {
+          runShellTask(folder, download, DOWNLOAD_COMMAND);
+        }
+      });
+  }
+
+  throw new Error(problem);
+}
@@ -2,24 +2,157 @@
  * Copyright (C) Microsoft Corporation. All rights reserved.
  *--------------------------------------------------------*/
 
+import styles from 'ansi-styles';
+import { basename } from 'path';
 import * as vscode from 'vscode';
-import { scanTestOutput } from './testOutputScanner';
-import { guessWorkspaceFolder, itemData, TestCase, TestFile } from './testTree';
-import { BrowserTestRunner, PlatformTestRunner, VSCodeTestRunner } from './vscodeTestRunner';
+import {
+  CoverageMap,
+  formatCoverage,
+  mergeCoverage,
+  readCoverage,
+  summarizeCoverage,
+  TestCoverage,
+} from './coverage';
+import { getChangedFiles, getHeadCommit, getMergeBase } from './git';
+import { ModuleGraph } from './moduleGraph';
+import {
+  configureProfile,
+  DEFAULT_PROFILE_OPTIONS,
+  IProfileOptions,
+  ProfileOptionsStore,
+} from './profileOptions';
+import {
+  CONFIG_SECTION,
+  getBrowsers,
+  getMaxParallelProcesses,
+  getReportDirectory,
+  getRetryCount,
+  getRunFailedFirst,
+  getTestFilePatterns,
+  getTestFunctionNames,
+  getTreeGrouping,
+  isTestFile,
+} from './settings';
+import { setTestFunctionNames } from './sourceUtils';
+import { BuildState, ensureFreshBuild } from './staleBuild';
+import { ITestResult, scanTestOutput, TestResolver } from './testOutputScanner';
+import { TestHistory } from './testHistory';
+import { ITestFailure, TestReport } from './testReport';
+import { runWithConcurrency, shardByFile } from './testScheduler';
+import { STOP_WATCHING_COMMAND, stopWatching, watchTests } from './testWatcher';
+import {
+  focusedTestDiagnostics,
+  getContainedTestFiles,
+  getLayer,
+  getSourceUriForCompiledFile,
+  guessWorkspaceFolder,
+  itemData,
+  setAnnotationProvider,
+  TestCase,
+  TestConstruct,
+  TestDynamic,
+  TestFile,
+  TestFolder,
+  updateDescription,
+} from './testTree';
+import {
+  BrowserTestRunner,
+  ExtensionHostTestRunner,
+  NodeTestRunner,
+  PlatformTestRunner,
+  VSCodeTestRunner,
+} from './vscodeTestRunner';
 
-const TEST_FILE_PATTERN = 'src/vs/**/*.test.ts';
+const getWorkspaceFolderForTestFile = (uri: vscode.Uri) => {
+  const folder = vscode.workspace.getWorkspaceFolder(uri);
+  return folder && isTestFile(folder, uri) ? folder : undefined;
+};
 
-const getWorkspaceFolderForTestFile = (uri: vscode.Uri) =>
-  uri.path.endsWith('.test.ts') ? vscode.workspace.getWorkspaceFolder(uri) : undefined;
+/**
+ * Number of times tests in a `flakySuite` are retried in a new process if
+ * they fail, when fewer retries are configured.
+ */
+const FLAKY_SUITE_RETRIES = 1;
 
-const browserArgs: [name: string, arg: string][] = [
-  ['Chrome', 'chromium'],
-  ['Firefox', 'firefox'],
-  ['Webkit', 'webkit'],
+type RunnerCtor = {
+  new (folder: vscode.WorkspaceFolder, options?: IProfileOptions): VSCodeTestRunner;
+  readonly supportsCoverage: boolean;
+};
+
+interface IRunHandlerOptions {
+  debug?: boolean;
+  /** Arguments for the test script of the profile's runner */
+  args?: string[];
+  /** Label of the profile whose configured options are used */
+  profile?: string;
+  /** Label of the profile whose failures the run replaces, the profile by default */
+  failuresOf?: string;
+  /** Whether coverage is collected regardless of the profile's options */
+  coverage?: boolean;
+  /**
+   * Whether to ask the user what to do when compiled output is stale, rather
+   * than warning in the run's output. Watch and rerun runs don't ask, since
+   * they'd ask again on every rerun.
+   */
+  promptOnStaleBuild?: boolean;
+}
+
+/**
+ * Tests that failed in a profile's last run, and how it ran them.
+ */
+interface IFailedRun {
+  runnerCtor: RunnerCtor;
+  debug: boolean;
+  args: string[];
+  /** URIs of the files of the tests, by test ID */
+  tests: Map<string, vscode.Uri>;
+}
+
+/**
+ * Tests that run together in one test process.
+ */
+interface IRunGroup {
+  runner: VSCodeTestRunner;
+  args: string[];
+  /** Tests that select what the process runs, or undefined to run everything */
+  filter?: ReadonlyArray<vscode.TestItem>;
+  /** Tests expected to report results, by their full title */
+  tests: Map<string, vscode.TestItem>;
+}
+
+/** Label of the profile that runs tests affected by the active file */
+const AFFECTED_PROFILE = 'Run Tests Affected by Current File';
+/** Label of the profile that reruns the tests that failed in the last run */
+const RERUN_PROFILE = 'Rerun Failed Tests';
+
+/**
+ * Runners that tests are sent to, in order, when the profile's runner can't
+ * load their layer.
+ */
+const FALLBACK_RUNNERS: ReadonlyArray<RunnerCtor> = [
+  PlatformTestRunner,
+  NodeTestRunner,
+  ExtensionHostTestRunner,
 ];
 
+/** Names shown in profiles for Playwright's browsers */
+const browserNames: { [browser: string]: string } = {
+  chromium: 'Chrome',
+  firefox: 'Firefox',
+  webkit: 'Webkit',
+};
+
+/** Results of past runs, which are saved before the extension deactivates */
+let testHistory: TestHistory | undefined;
+
+const updateTestFunctionNames = () => {
+  const { suites, flakySuites, tests } = getTestFunctionNames();
+  setTestFunctionNames(suites, flakySuites, tests);
+};
+
 export async function activate(context: vscode.ExtensionContext) {
   const ctrl = vscode.test.createTestController('selfhost-test-controller', 'VS Code Tests');
+  updateTestFunctionNames();
 
   ctrl.resolveChildrenHandler = async test => {
     const data = itemData.get(test);
@@ -30,63 +163,695 @@ export async function activate(context: vscode.ExtensionContext) {
     }
   };
 
-  let runQueue = Promise.resolve();
+  const resolveTest: TestResolver = (fullTitle, file) => {
+    const fileItem = file ? getOrCreateFile(ctrl, getSourceUriForCompiledFile(file)) : undefined;
+    const data = fileItem && itemData.get(fileItem);
+    return data instanceof TestFile ? data.createRuntimeTest(fileItem!, fullTitle) : undefined;
+  };
+
+  /** Runs of each runner type, so that runs of the same runner don't overlap */
+  const runQueues = new Map<unknown, Promise<void>>();
+  // history is loaded in the background so it doesn't hold up activation,
+  // and is only waited for by runs, which record results in it
+  const loadingHistory = TestHistory.load(context.globalStorageUri).then(loaded => {
+    testHistory = loaded;
+    context.subscriptions.push(loaded);
+    updateAllDescriptions(ctrl.items.all);
+    return loaded;
+  });
+
+  const coverage = new TestCoverage();
+  context.subscriptions.push(coverage);
+
+  /** Folder paths in the tree of source files with coverage, by their URI */
+  const sourceFolderPaths = new Map<string, string>();
+  const getSourceFolderPath = (uri: vscode.Uri) => {
+    const key = uri.toString();
+    let folderPath = sourceFolderPaths.get(key);
+    if (folderPath === undefined) {
+      const folder = vscode.workspace.getWorkspaceFolder(uri);
+      folderPath = folder
+        ? new TestFile(uri, folder).getFolderPath(getTreeGrouping()).join('/')
+        : '';
+      sourceFolderPaths.set(key, folderPath);
+    }
+
+    return folderPath;
+  };
+
+  /**
+   * Summarizes coverage of the sources a folder or file in the tree tests.
+   * Sources are matched by being shown in the same folders as the tests,
+   * and for files, by having the test file's name without `.test`.
+   */
+  const getCoverageSummary = (item: vscode.TestItem) => {
+    const data = itemData.get(item);
+    if (coverage.isEmpty) {
+      return undefined;
+    } else if (data instanceof TestFolder) {
+      const folderPath = data.path.join('/');
+      return coverage.summarize(uri => {
+        const sourcePath = getSourceFolderPath(uri);
+        return sourcePath === folderPath || sourcePath.startsWith(folderPath + '/');
+      });
+    } else if (data instanceof TestFile) {
+      const folderPath = data.getFolderPath(getTreeGrouping()).join('/');
+      const name = data.getLabel().replace(/\.(test|integrationTest)\.ts$/, '.ts');
+      return coverage.summarize(
+        uri => basename(uri.fsPath) === name && getSourceFolderPath(uri) === folderPath
+      );
+    }
+
+    return undefined;
+  };
+
+  setAnnotationProvider(item => {
+    const parts: string[] = [];
+    const flakiness = testHistory?.getFlakiness(item.id);
+    if (flakiness) {
+      parts.push(`flaky in ${Math.round(flakiness * 100)}% of recent runs`);
+    }
+
+    const summary = getCoverageSummary(item);
+    if (summary) {
+      parts.push(`${formatCoverage(summary)} lines covered`);
+    }
+
+    return parts.length ? parts.join(', ') : undefined;
+  });
+
+  /** Updates descriptions of folders and files in the tree, such as their coverage */
+  const updateTreeDescriptions = (items: ReadonlyArray<vscode.TestItem>) => {
+    for (const item of items) {
+      const data = itemData.get(item);
+      if (data instanceof TestFolder || data instanceof TestFile) {
+        updateDescription(item);
+        updateTreeDescriptions(item.children.all);
+      }
+    }
+  };
+
+  /** Updates descriptions of every item in the tree, such as their flakiness */
+  const updateAllDescriptions = (items: ReadonlyArray<vscode.TestItem>) => {
+    for (const item of items) {
+      updateDescription(item);
+      updateAllDescriptions(item.children.all);
+    }
+  };
+
+  coverage.onDidChange(() => {
+    sourceFolderPaths.clear();
+    updateTreeDescriptions(ctrl.items.all);
+  });
+
+  let moduleGraph: ModuleGraph | undefined;
+  const getModuleGraph = (folder: vscode.WorkspaceFolder) => {
+    if (!moduleGraph) {
+      moduleGraph = new ModuleGraph(folder, uri => !!getWorkspaceFolderForTestFile(uri));
+      context.subscriptions.push(moduleGraph);
+    }
+
+    return moduleGraph;
+  };
+
+  const profileOptions = new ProfileOptionsStore(context.workspaceState);
+
+  /** Tests that failed in the last run of each profile, by the profile's label */
+  const lastFailures = new Map<string, IFailedRun>();
+
+  /** Shows coverage collected in a run, and summarizes it in the run's output */
+  const showCoverage = (task: vscode.TestRun, runCoverage: CoverageMap) => {
+    if (!runCoverage.size) {
+      task.appendOutput(
+        `${styles.yellow.open}No coverage was reported. Coverage is collected by the ` +
+          `Electron and Node.js test scripts.${styles.yellow.close}\r\n`
+      );
+      return;
+    }
+
+    coverage.set(runCoverage);
+    const summary = summarizeCoverage(runCoverage.values());
+    task.appendOutput(
+      `Coverage: ${formatCoverage(summary)} of lines in ${runCoverage.size} file(s)\r\n`
+    );
+  };
+
+  /**
+   * Creates a handler that runs tests with the runner. If the profile's
+   * label is given, the options the user configured for it are used.
+   */
   const createRunHandler = (
-    runnerCtor: { new (folder: vscode.WorkspaceFolder): VSCodeTestRunner },
-    debug: boolean,
-    args: string[] = []
-  ): vscode.TestRunHandler => async (req, cancellationToken) => {
+    runnerCtor: RunnerCtor,
+    {
+      debug = false,
+      args = [],
+      profile,
+      failuresOf = profile,
+      coverage: withCoverage = false,
+      promptOnStaleBuild = true,
+    }: IRunHandlerOptions = {}
+  ): vscode.TestRunHandler => {
+    const handler: vscode.TestRunHandler = async (req, cancellationToken) => {
+      const folder = await guessWorkspaceFolder();
+      if (!folder) {
+        return;
+      }
+
+      const history = await loadingHistory;
+      const options = profile ? profileOptions.get(profile) : DEFAULT_PROFILE_OPTIONS;
+      // only the scripts of some runners can collect coverage
+      const collectCoverage = (withCoverage || options.coverage) && runnerCtor.supportsCoverage;
+      const createRunner = (ctor: RunnerCtor) =>
+        new ctor(folder, { ...options, coverage: collectCoverage && ctor.supportsCoverage });
+      const runner = createRunner(runnerCtor);
+      // Debugging several processes at once isn't supported, and processes
+      // collecting coverage write it to the same place
+      const parallelism = debug || collectCoverage ? 1 : getMaxParallelProcesses();
+
+      // Tests the profile's runner can't load are sent to one that can. When
+      // running everything, the runner already only loads files it supports.
+      const runners = [runner];
+      if (req.include) {
+        for (const ctor of FALLBACK_RUNNERS.filter(c => c !== runnerCtor)) {
+          runners.push(createRunner(ctor));
+        }
+      }
+      const { byRunner, unsupported } = partitionByLayer(runners, req.include ?? ctrl.items.all);
+
+      /** Splits the runner's tests into the groups that each run in a process */
+      const createGroups = (
+        groupRunner: VSCodeTestRunner,
+        tests: ReadonlyArray<vscode.TestItem>,
+        runAll: boolean,
+        shard: boolean
+      ): Promise<IRunGroup[]> =>
+        Promise.all(
+          groupRunner
+            .groupByProcess(tests, runAll)
+            .flatMap(filter =>
+              shard && parallelism > 1
+                ? shardByFile(filter ?? tests, parallelism, history.fileDurations)
+                : [filter]
+            )
+            .map(async filter => ({
+              runner: groupRunner,
+              // the profile's arguments, such as the browser, are only for its own runner
+              args: groupRunner === runner ? args : [],
+              filter,
+              tests: await getPendingTestMap(filter ?? tests),
+            }))
+        );
+
+      // Tests that failed last time run in their own processes first, and
+      // then the rest of the files and suites they're in, without them
+      const lastFailed = getRunFailedFirst() && failuresOf && lastFailures.get(failuresOf);
+      const firstGroups: IRunGroup[] = [];
+      const laterGroups: IRunGroup[] = [];
+      for (const [groupRunner, tests] of byRunner) {
+        const pending = lastFailed ? [...(await getPendingTestMap(tests)).values()] : [];
+        const failedBefore = pending.filter(test => lastFailed && lastFailed.tests.has(test.id));
+        if (!lastFailed || !failedBefore.length) {
+          laterGroups.push(...(await createGroups(groupRunner, tests, !req.include, true)));
+          continue;
+        }
+
+        const rest = excludeTests(tests, lastFailed.tests);
+        firstGroups.push(...(await createGroups(groupRunner, failedBefore, false, false)));
+        if (rest.length) {
+          laterGroups.push(...(await createGroups(groupRunner, rest, false, true)));
+        }
+      }
+      const groups = [...firstGroups, ...laterGroups];
+
+      /** Splits tests into groups for the runners that can load them, without sharding */
+      const regroup = async (tests: ReadonlyArray<vscode.TestItem>) =>
+        (
+          await Promise.all(
+            [...partitionByLayer(runners, tests).byRunner].map(([r, runnerTests]) =>
+              createGroups(r, runnerTests, false, false)
+            )
+          )
+        ).flat();
+
+      // check the compiled output of the tests, and everything they import, is up to date
+      const testFiles = new Map<string, vscode.Uri>();
+      for (const { tests } of groups) {
+        for (const test of tests.values()) {
+          testFiles.set(test.uri!.toString(), test.uri!);
+        }
+      }
+      const sources = await getModuleGraph(folder).getImportedFiles([...testFiles.values()]);
+      const buildState = await ensureFreshBuild(folder, sources, promptOnStaleBuild);
+      if (buildState === BuildState.Cancelled) {
+        return;
+      }
+
+      const skipped = await getPendingTestMap(unsupported);
+      const isStale = buildState === BuildState.Stale;
+      const task = ctrl.createTestRun(req, isStale ? 'Run against stale build output' : undefined);
+      if (isStale) {
+        task.appendOutput(
+          `${styles.yellow.open}Warning: some sources changed since they were compiled, ` +
+            `results may not match the code.${styles.yellow.close}\r\n`
+        );
+      }
+      for (const { tests } of groups) {
+        for (const test of tests.values()) {
+          task.setState(test, vscode.TestResultState.Queued);
+        }
+      }
+
+      // when running everything, only the profile's own runner is tried
+      const triedIn = runners.length > 1 ? 'any test environment' : runner.environment;
+      for (const test of skipped.values()) {
+        const layer = getLayer(test.uri!);
+        const message = `Tests in the "${layer}" layer can't run in ${triedIn}.`;
+        task.appendMessage(test, new vscode.TestMessage(message));
+        task.setState(test, vscode.TestResultState.Skipped);
+      }
+
+      const historyRun = history.startRun(await getHeadCommit(folder));
+      const reportDirectory = getReportDirectory(folder);
+      const report = reportDirectory ? new TestReport(folder) : undefined;
+      const runCoverage: CoverageMap = new Map();
+      const runDurations = new Map<string, number>();
+      const failed = new Set<vscode.TestItem>();
+
+      // Retries would each start a new debug session, so they're off when debugging
+      const retries = debug ? 0 : options.retries ?? getRetryCount();
+      /** Failures of tests that will be retried, by test */
+      const retrying = new Map<vscode.TestItem, vscode.TestMessage[]>();
+      let attempt = 0;
+
+      const deferFailure = (test: vscode.TestItem, message: vscode.TestMessage) => {
+        if (debug || attempt >= getRetriesForTest(test, retries)) {
+          return false;
+        }
+
+        retrying.set(test, [...(retrying.get(test) ?? []), message]);
+        // reported as failed in case the retry never runs, such as when the
+        // run is cancelled, and replaced by the retry's result otherwise
+        report?.addResult(
+          test,
+          vscode.TestResultState.Failed,
+          undefined,
+          false,
+          toReportFailure(message)
+        );
+        return true;
+      };
+
+      const onResult = ({ test, state, duration, flaky, failure }: ITestResult) => {
+        const failures = retrying.get(test);
+        retrying.delete(test);
+        if (failures && state === vscode.TestResultState.Passed) {
+          task.appendMessage(test, createFlakyMessage(failures));
+          flaky = true;
+        } else if (failures) {
+          failures.forEach(message => task.appendMessage(test, message));
+          // a retried test that didn't run again still failed before
+          if (state === vscode.TestResultState.Skipped) {
+            state = vscode.TestResultState.Failed;
+            failure = toReportFailure(failures[failures.length - 1]);
+            task.setState(test, state);
+          }
+        }
+
+        report?.addResult(test, state, duration, flaky, failure);
+
+        if (state === vscode.TestResultState.Failed || state === vscode.TestResultState.Errored) {
+          failed.add(test);
+        }
+
+        history.recordResult(test.id, { state, duration, run: historyRun, flaky });
+        updateDescription(test);
+        if (test.uri && duration !== undefined) {
+          const key = test.uri.toString();
+          runDurations.set(key, (runDurations.get(key) ?? 0) + duration);
+        }
+      };
+
+      const runGroup = async (group: IRunGroup) => {
+        const { filter, tests } = group;
+        // tests in a cancelled run are left without a result
+        if (cancellationToken.isCancellationRequested) {
+          return;
+        }
+
+        const started = Date.now();
+        try {
+          await scanTestOutput(
+            tests,
+            task,
+            debug
+              ? await group.runner.debug(group.args, filter)
+              : await group.runner.run(group.args, filter),
+            cancellationToken,
+            {
+              resolveTest,
+              onResult,
+              deferFailure,
+              waitForExit: collectCoverage,
+            }
+          );
+        } catch (e) {
+          // the test process couldn't be started, so none of the tests ran
+          task.appendOutput(`${e.stack || e.message}\r\n`);
+          for (const test of tests.values()) {
+            task.appendMessage(test, new vscode.TestMessage(e.message));
+            task.setState(test, vscode.TestResultState.Errored);
+            report?.addResult(test, vscode.TestResultState.Errored, undefined, false, {
+              message: e.message,
+            });
+          }
+        }
+
+        if (collectCoverage) {
+          mergeCoverage(runCoverage, await readCoverage(folder, started));
+        }
+      };
+
+      /** Reruns failed tests that have retries left, each time in new processes */
+      const runRetries = async () => {
+        const maxRetries = Math.max(retries, FLAKY_SUITE_RETRIES);
+        while (
+          retrying.size &&
+          attempt < maxRetries &&
+          !cancellationToken.isCancellationRequested
+        ) {
+          attempt++;
+          const toRetry = [...retrying.keys()];
+          task.appendOutput(
+            `Retrying ${toRetry.length} failed test(s), attempt ${attempt + 1}\r\n`
+          );
+          const retryGroups = await regroup(toRetry);
+          await runWithConcurrency(retryGroups.map(group => () => runGroup(group)), parallelism);
+        }
+
+        // tests whose retries were cancelled keep their last failure
+        for (const [test, failures] of retrying) {
+          failures.forEach(message => task.appendMessage(test, message));
+          task.setState(test, vscode.TestResultState.Failed);
+          failed.add(test);
+        }
+      };
+
+      const previous = runQueues.get(runnerCtor) ?? Promise.resolve();
+      const run = previous.then(async () => {
+        try {
+          await runWithConcurrency(groups.map(group => () => runGroup(group)), parallelism);
+          await runRetries();
+        } finally {
+          if (collectCoverage) {
+            showCoverage(task, runCoverage);
+          }
+          if (report) {
+            await writeReport(task, report, reportDirectory);
+          }
+          task.end();
+          for (const [file, duration] of runDurations) {
+            history.recordFileDuration(file, duration);
+          }
+
+          if (failuresOf) {
+            lastFailures.set(failuresOf, {
+              runnerCtor,
+              debug,
+              args,
+              tests: new Map([...failed].map(test => [test.id, test.uri!])),
+            });
+          }
+        }
+      });
+
+      runQueues.set(runnerCtor, run);
+      return run;
+    };
+
+    return handler;
+  };
+
+  /**
+   * Finds tests by their IDs in the files they were in, reading files that
+   * haven't been read yet. Tests that no longer exist are left out.
+   */
+  const findTests = async (tests: ReadonlyMap<string, vscode.Uri>) => {
+    const files = new Map<string, vscode.TestItem>();
+    for (const uri of tests.values()) {
+      const file = getOrCreateFile(ctrl, uri);
+      if (file) {
+        files.set(file.id, file);
+      }
+    }
+
+    const found: vscode.TestItem[] = [];
+    const visit = (item: vscode.TestItem) => {
+      if (tests.has(item.id)) {
+        found.push(item);
+      } else {
+        item.children.all.forEach(visit);
+      }
+    };
+
+    for (const file of files.values()) {
+      const data = itemData.get(file);
+      if (data instanceof TestFile && !data.hasBeenRead) {
+        await data.updateFromDisk(file);
+      }
+      visit(file);
+    }
+
+    return found;
+  };
+
+  /**
+   * Reruns the tests that failed in the last run of a profile, asking which
+   * if several profiles have failures. If a request is given, only failed
+   * tests it includes are rerun.
+   */
+  const rerunFailed = async (token: vscode.CancellationToken, req?: vscode.TestRunRequest) => {
+    const labels = [...lastFailures].filter(([, run]) => run.tests.size).map(([label]) => label);
+    if (!labels.length) {
+      vscode.window.showInformationMessage('No tests failed in the last run.');
+      return;
+    }
+
+    const label =
+      labels.length === 1
+        ? labels[0]
+        : await vscode.window.showQuickPick(labels, {
+            placeHolder: 'Rerun the failed tests of which profile?',
+          });
+    const run = label && lastFailures.get(label);
+    if (!run) {
+      return;
+    }
+
+    const tests = (await findTests(run.tests)).filter(test => !req || isInRequest(test, req));
+    if (!tests.length) {
+      vscode.window.showInformationMessage('None of the selected tests failed in the last run.');
+      return;
+    }
+
+    // the options configured for this profile apply, and the failures left
+    // by the rerun replace those of the profile that first ran the tests
+    const handler = createRunHandler(run.runnerCtor, {
+      debug: run.debug,
+      args: run.args,
+      profile: RERUN_PROFILE,
+      failuresOf: label,
+      promptOnStaleBuild: false,
+    });
+    await handler(new vscode.TestRunRequest(tests), token);
+  };
+
+  /** Gets test files affected by changes to the files */
+  const getAffectedTestFiles = async (changed: ReadonlyArray<vscode.Uri>) => {
+    const folder = await guessWorkspaceFolder();
+    return folder ? getModuleGraph(folder).getAffectedTestFiles(changed) : [];
+  };
+
+  /** Gets which of the tests are in files affected by changes to the sources */
+  const getAffectedTests = async (
+    tests: ReadonlyArray<vscode.TestItem>,
+    sources: ReadonlyArray<vscode.Uri>
+  ) => {
+    const files = new Set(
+      (await getAffectedTestFiles(sources)).map(uri => uri.toString().toLowerCase())
+    );
+    return tests
+      .flatMap(test =>
+        itemData.get(test) instanceof TestFolder ? getContainedTestFiles(test) : [test]
+      )
+      .filter(test => test.uri && files.has(test.uri.toString().toLowerCase()));
+  };
+
+  // tests Electron can't load are sent to the runner for their layer
+  const runAffected = createRunHandler(PlatformTestRunner, { profile: AFFECTED_PROFILE });
+
+  /**
+   * Runs the test files, each with a runner that can load its layer. If a
+   * request is given, only files it includes are run.
+   */
+  const runTestFiles = async (
+    uris: ReadonlyArray<vscode.Uri>,
+    token: vscode.CancellationToken,
+    req?: vscode.TestRunRequest
+  ) => {
+    const files = uris
+      .map(uri => getOrCreateFile(ctrl, uri))
+      .filter((item): item is vscode.TestItem => !!item && (!req || isInRequest(item, req)));
+    if (!files.length) {
+      vscode.window.showInformationMessage('No tests are affected by the changes.');
+      return;
+    }
+
+    await runAffected(new vscode.TestRunRequest(files, req?.exclude), token);
+  };
+
+  /** Runs tests affected by changes in the working tree, since HEAD or the merge base */
+  const runAffectedByChanges = async (token: vscode.CancellationToken) => {
     const folder = await guessWorkspaceFolder();
     if (!folder) {
       return;
     }
 
-    const runner = new runnerCtor(folder);
-    const map = await getPendingTestMap(req.include ?? ctrl.items.all);
-    const task = ctrl.createTestRun(req);
-    for (const test of map.values()) {
-      task.setState(test, vscode.TestResultState.Queued);
+    const since = await vscode.window.showQuickPick(
+      [
+        { label: 'Uncommitted Changes', description: 'Changes since HEAD', mergeBase: false },
+        {
+          label: 'Branch Changes',
+          description: 'Changes since the merge base with main',
+          mergeBase: true,
+        },
+      ],
+      { placeHolder: 'Run tests affected by which changes?' }
+    );
+    if (!since) {
+      return;
     }
 
-    return (runQueue = runQueue.then(async () => {
-      await scanTestOutput(
-        map,
-        task,
-        debug ? await runner.debug(args, req.include) : await runner.run(args, req.include),
-        cancellationToken
-      );
-    }));
+    const commit = since.mergeBase ? await getMergeBase(folder) : 'HEAD';
+    if (!commit) {
+      vscode.window.showErrorMessage('Could not find the merge base with the main branch.');
+      return;
+    }
+
+    try {
+      const changed = await getChangedFiles(folder, commit);
+      await runTestFiles(await getAffectedTestFiles(changed), token);
+    } catch (e) {
+      vscode.window.showErrorMessage(e.message);
+    }
   };
 
-  ctrl.createRunProfile(
-    'Run in Electron',
-    vscode.TestRunProfileGroup.Run,
-    createRunHandler(PlatformTestRunner, false),
-    true
-  );
+  /** Creates a handler that runs tests, and then reruns them as they're rebuilt */
+  const createWatchHandler = (runHandler: vscode.TestRunHandler): vscode.TestRunHandler => async (
+    req,
+    token
+  ) => {
+    const folder = await guessWorkspaceFolder();
+    if (!folder) {
+      return;
+    }
 
-  ctrl.createRunProfile(
-    'Debug in Electron',
-    vscode.TestRunProfileGroup.Debug,
-    createRunHandler(PlatformTestRunner, true),
-    true
-  );
+    await watchTests(folder, req.include ?? ctrl.items.all, token, {
+      run: async (tests, runToken) => {
+        const request = tests ? new vscode.TestRunRequest([...tests], req.exclude) : req;
+        await runHandler(request, runToken);
+      },
+      getAffected: getAffectedTests,
+    });
+  };
 
-  for (const [name, arg] of browserArgs) {
-    const cfg = ctrl.createRunProfile(
-      `Run in ${name}`,
-      vscode.TestRunProfileGroup.Run,
-      createRunHandler(BrowserTestRunner, false, [' --browser', arg])
+  /**
+   * Creates a profile that runs tests with the runner, whose options the
+   * user can configure.
+   */
+  const createProfile = (
+    label: string,
+    group: vscode.TestRunProfileGroup,
+    runnerCtor: RunnerCtor,
+    {
+      debug = false,
+      watch = false,
+      coverage = false,
+      isDefault = false,
+      args = [] as string[],
+    } = {}
+  ) => {
+    const handler = createRunHandler(runnerCtor, {
+      debug,
+      args,
+      profile: label,
+      coverage,
+      promptOnStaleBuild: !watch,
+    });
+    const profile = ctrl.createRunProfile(
+      label,
+      group,
+      watch ? createWatchHandler(handler) : handler,
+      isDefault
     );
+    profile.configureHandler = () =>
+      configureProfile(profileOptions, label, {
+        headed: runnerCtor === BrowserTestRunner,
+        coverage: runnerCtor.supportsCoverage,
+      });
+    return profile;
+  };
 
-    cfg.configureHandler = () => vscode.window.showInformationMessage(`Configuring ${name}`);
+  const { Run, Debug, Coverage } = vscode.TestRunProfileGroup;
+  createProfile('Run in Electron', Run, PlatformTestRunner, { isDefault: true });
+  createProfile('Debug in Electron', Debug, PlatformTestRunner, { debug: true, isDefault: true });
+  createProfile('Run in Node.js', Run, NodeTestRunner);
+  createProfile('Debug in Node.js', Debug, NodeTestRunner, { debug: true });
+  createProfile('Run in Extension Host', Run, ExtensionHostTestRunner);
+  createProfile('Debug in Extension Host', Debug, ExtensionHostTestRunner, { debug: true });
+  createProfile('Watch in Electron', Run, PlatformTestRunner, { watch: true });
+  createProfile('Watch in Node.js', Run, NodeTestRunner, { watch: true });
+  createProfile('Run with Coverage', Coverage, PlatformTestRunner, {
+    coverage: true,
+    isDefault: true,
+  });
 
-    ctrl.createRunProfile(
-      `Debug in ${name}`,
-      vscode.TestRunProfileGroup.Debug,
-      createRunHandler(BrowserTestRunner, false, ['--browser', arg, '--debug-browser'])
-    );
-  }
+  const affectedProfile = ctrl.createRunProfile(AFFECTED_PROFILE, Run, async (req, token) => {
+    const uri = vscode.window.activeTextEditor?.document.uri;
+    if (uri) {
+      await runTestFiles(await getAffectedTestFiles([uri]), token, req);
+    } else {
+      vscode.window.showInformationMessage('Open a file to run the tests affected by it.');
+    }
+  });
+  affectedProfile.configureHandler = () =>
+    configureProfile(profileOptions, AFFECTED_PROFILE, {
+      headed: false,
+      coverage: PlatformTestRunner.supportsCoverage,
+    });
+
+  const rerunProfile = ctrl.createRunProfile(RERUN_PROFILE, Run, (req, token) =>
+    rerunFailed(token, req)
+  );
+  // failed tests of any runner can be rerun, so every option is offered
+  rerunProfile.configureHandler = () =>
+    configureProfile(profileOptions, RERUN_PROFILE, { headed: true, coverage: true });
+
+  let browserProfiles: vscode.TestRunProfile[] = [];
+  const createBrowserProfiles = () => {
+    browserProfiles.forEach(p => p.dispose());
+    browserProfiles = getBrowsers().flatMap(browser => {
+      const name = browserNames[browser] ?? browser;
+      // debugging browsers other than Chromium fails each test with why
+      return [
+        createProfile(`Run in ${name}`, Run, BrowserTestRunner, { args: ['--browser', browser] }),
+        createProfile(`Debug in ${name}`, Debug, BrowserTestRunner, {
+          debug: true,
+          args: ['--browser', browser, '--debug-browser'],
+        }),
+      ];
+    });
+  };
+  createBrowserProfiles();
 
   function updateNodeForDocument(e: vscode.TextDocument) {
     const node = getOrCreateFile(ctrl, e.uri);
@@ -100,13 +865,157 @@ export async function activate(context: vscode.ExtensionContext) {
     updateNodeForDocument(document);
   }
 
+  function regroupTree() {
+    const files = ctrl.items.all.flatMap(getContainedTestFiles).map(item => item.uri!);
+    ctrl.items.all = [];
+    for (const uri of files) {
+      getOrCreateFile(ctrl, uri);
+    }
+    for (const document of vscode.workspace.textDocuments) {
+      updateNodeForDocument(document);
+    }
+  }
+
+  let discovery = startWatchingWorkspace(ctrl);
+  await discovery;
+
+  /** Finds tests again from scratch, after the settings for finding them changed */
+  function restartDiscovery() {
+    discovery = discovery.then(async previous => {
+      previous.dispose();
+      ctrl.items.all = [];
+      updateTestFunctionNames();
+      const next = await startWatchingWorkspace(ctrl);
+      for (const document of vscode.workspace.textDocuments) {
+        updateNodeForDocument(document);
+      }
+      return next;
+    });
+  }
+
   context.subscriptions.push(
     vscode.workspace.onDidOpenTextDocument(updateNodeForDocument),
     vscode.workspace.onDidChangeTextDocument(e => updateNodeForDocument(e.document)),
-    await startWatchingWorkspace(ctrl)
+    vscode.workspace.onDidChangeConfiguration(e => {
+      if (e.affectsConfiguration(`${CONFIG_SECTION}.discovery`)) {
+        restartDiscovery();
+      } else if (e.affectsConfiguration(`${CONFIG_SECTION}.treeGrouping`)) {
+        sourceFolderPaths.clear();
+        regroupTree();
+      }
+      if (e.affectsConfiguration(`${CONFIG_SECTION}.browsers`)) {
+        createBrowserProfiles();
+      }
+    }),
+    vscode.commands.registerCommand('selfhost-test-provider.rerunFailed', async () => {
+      const cts = new vscode.CancellationTokenSource();
+      try {
+        await rerunFailed(cts.token);
+      } finally {
+        cts.dispose();
+      }
+    }),
+    vscode.commands.registerCommand(STOP_WATCHING_COMMAND, stopWatching),
+    vscode.commands.registerCommand('selfhost-test-provider.clearCoverage', () =>
+      coverage.clear()
+    ),
+    vscode.commands.registerCommand('selfhost-test-provider.runAffected', async () => {
+      const cts = new vscode.CancellationTokenSource();
+      try {
+        await runAffectedByChanges(cts.token);
+      } finally {
+        cts.dispose();
+      }
+    }),
+    focusedTestDiagnostics,
+    new vscode.Disposable(() => discovery.then(d => d.dispose()))
   );
 }
 
+export function deactivate() {
+  // results of the last run may not have been saved yet
+  return testHistory?.flush();
+}
+
+const getRetriesForTest = (test: vscode.TestItem, retries: number) => {
+  const data = itemData.get(test);
+  return data instanceof TestConstruct && data.flaky
+    ? Math.max(retries, FLAKY_SUITE_RETRIES)
+    : retries;
+};
+
+const getMessageText = ({ message }: vscode.TestMessage) =>
+  typeof message === 'string' ? message : message.value;
+
+const toReportFailure = (message: vscode.TestMessage): ITestFailure => ({
+  message: getMessageText(message),
+  expected: message.expectedOutput,
+  actual: message.actualOutput,
+});
+
+/**
+ * Writes the run's results to the report directory, noting where in the
+ * run's output.
+ */
+const writeReport = async (task: vscode.TestRun, report: TestReport, directory: string) => {
+  try {
+    const files = await report.write(directory);
+    task.appendOutput(`Wrote results to ${files.join(' and ')}\r\n`);
+  } catch (e) {
+    const message = `Could not write results to ${directory}: ${e.message}`;
+    task.appendOutput(`${styles.red.open}${message}${styles.red.close}\r\n`);
+  }
+};
+
+/**
+ * Creates the message for a test that failed, but then passed when retried.
+ */
+const createFlakyMessage = (failures: ReadonlyArray<vscode.TestMessage>) => {
+  const attempts = failures.map(
+    (failure, i) => `Attempt ${i + 1} failed:\n\n${getMessageText(failure)}`
+  );
+
+  return new vscode.TestMessage(
+    [
+      `Flaky: this test failed ${failures.length} time(s) before passing.`,
+      ...attempts,
+      `Attempt ${failures.length + 1} passed.`,
+    ].join('\n\n')
+  );
+};
+
+/**
+ * Gets the chain of folder items the file is shown under, from the root
+ * down. If `create` is false, returns undefined if any of them don't exist.
+ */
+function getFolderChain(
+  controller: vscode.TestController,
+  file: TestFile,
+  create: boolean
+): vscode.TestItem[] | undefined {
+  const chain: vscode.TestItem[] = [];
+  const path = file.getFolderPath(getTreeGrouping());
+  for (let i = 0; i < path.length; i++) {
+    const collection = i === 0 ? controller.items : chain[i - 1].children;
+    const data = new TestFolder(path.slice(0, i + 1));
+    let folder = collection.get(data.getId());
+    if (!folder) {
+      if (!create) {
+        return undefined;
+      }
+
+      folder = vscode.test.createTestItem(data.getId(), data.getLabel());
+      itemData.set(folder, data);
+      updateDescription(folder);
+      collection.add(folder);
+    }
+
+    chain.push(folder);
+  }
+
+  return chain;
+}
+
 function getOrCreateFile(
   controller: vscode.TestController,
   uri: vscode.Uri
@@ -117,38 +1026,159 @@ function getOrCreateFile(
   }
 
   const data = new TestFile(uri, folder);
-  const existing = controller.items.get(data.getId());
+  const chain = getFolderChain(controller, data, true)!;
+  const collection = chain.length ? chain[chain.length - 1].children : controller.items;
+  const existing = collection.get(data.getId());
   if (existing) {
     return existing;
   }
 
   const file = vscode.test.createTestItem(data.getId(), data.getLabel(), uri);
-  controller.items.add(file);
+  collection.add(file);
   file.canResolveChildren = true;
   itemData.set(file, data);
+  updateDescription(file);
 
   return file;
 }
 
+function removeFile(controller: vscode.TestController, uri: vscode.Uri) {
+  focusedTestDiagnostics.delete(uri);
+
+  const folder = getWorkspaceFolderForTestFile(uri);
+  const data = folder && new TestFile(uri, folder);
+  const chain = data && getFolderChain(controller, data, false);
+  if (!chain) {
+    return;
+  }
+
+  (chain.length ? chain[chain.length - 1].children : controller.items).delete(data!.getId());
+
+  // remove folders that no longer contain any tests
+  for (let i = chain.length - 1; i >= 0 && !chain[i].children.all.length; i--) {
+    (i === 0 ? controller.items : chain[i - 1].children).delete(chain[i].id);
+  }
+}
+
+/**
+ * Updates tests in a file that changed on disk, if they've been read and
+ * aren't already kept up to date by an open editor.
+ */
+function refreshFileFromDisk(controller: vscode.TestController, uri: vscode.Uri) {
+  const item = getOrCreateFile(controller, uri);
+  const data = item && itemData.get(item);
+  const isOpen = vscode.workspace.textDocuments.some(d => d.uri.toString() === uri.toString());
+  if (data instanceof TestFile && data.hasBeenRead && !isOpen) {
+    data.updateFromDisk(item!);
+  }
+}
+
 async function startWatchingWorkspace(controller: vscode.TestController) {
   const workspaceFolder = await guessWorkspaceFolder();
   if (!workspaceFolder) {
     return new vscode.Disposable(() => undefined);
   }
 
-  const pattern = new vscode.RelativePattern(workspaceFolder, TEST_FILE_PATTERN);
-  const watcher = vscode.workspace.createFileSystemWatcher(pattern);
-  const contentChange = new vscode.EventEmitter<vscode.Uri>();
+  const watchers = await Promise.all(
+    getTestFilePatterns().map(async glob => {
+      const pattern = new vscode.RelativePattern(workspaceFolder, glob);
+      const watcher = vscode.workspace.createFileSystemWatcher(pattern);
+
+      watcher.onDidCreate(uri => getOrCreateFile(controller, uri));
+      watcher.onDidChange(uri => refreshFileFromDisk(controller, uri));
+      watcher.onDidDelete(uri => removeFile(controller, uri));
+
+      for (const file of await vscode.workspace.findFiles(pattern)) {
+        getOrCreateFile(controller, file);
+      }
+
+      return watcher;
+    })
+  );
+
+  return vscode.Disposable.from(...watchers);
+}
 
-  watcher.onDidCreate(uri => getOrCreateFile(controller, uri));
-  watcher.onDidChange(uri => contentChange.fire(uri));
-  watcher.onDidDelete(uri => controller.items.delete(uri.toString()));
+/**
+ * Assigns each test to the first of the runners that can load its layer.
+ * Folders the first runner can run entirely are kept whole, others are split
+ * into their files. Tests none of the runners can load are returned apart.
+ */
+function partitionByLayer(
+  runners: ReadonlyArray<VSCodeTestRunner>,
+  tests: ReadonlyArray<vscode.TestItem>
+) {
+  const byRunner = new Map<VSCodeTestRunner, vscode.TestItem[]>();
+  const unsupported: vscode.TestItem[] = [];
+  const add = (runner: VSCodeTestRunner | undefined, test: vscode.TestItem) => {
+    if (runner) {
+      byRunner.set(runner, [...(byRunner.get(runner) ?? []), test]);
+    } else {
+      unsupported.push(test);
+    }
+  };
+  const findRunner = (test: vscode.TestItem) =>
+    test.uri ? runners.find(r => r.canRunLayer(getLayer(test.uri!))) : runners[0];
+
+  for (const test of tests) {
+    if (!(itemData.get(test) instanceof TestFolder)) {
+      add(findRunner(test), test);
+      continue;
+    }
 
-  for (const file of await vscode.workspace.findFiles(pattern)) {
-    getOrCreateFile(controller, file);
+    const files = getContainedTestFiles(test);
+    if (files.every(file => findRunner(file) === runners[0])) {
+      add(runners[0], test);
+    } else {
+      files.forEach(file => add(findRunner(file), file));
+    }
   }
 
-  return watcher;
+  return { byRunner, unsupported };
+}
+
+/**
+ * Gets the items, or the items in them, that hold everything in the items
+ * except the tests with the IDs. Items without any of those tests are kept
+ * whole, so that tests only found at runtime in them still run.
+ */
+function excludeTests(
+  tests: ReadonlyArray<vscode.TestItem>,
+  ids: ReadonlyMap<string, unknown>
+): vscode.TestItem[] {
+  const contains = (item: vscode.TestItem): boolean =>
+    ids.has(item.id) || item.children.all.some(contains);
+
+  return tests.flatMap(test => {
+    if (ids.has(test.id)) {
+      return [];
+    }
+
+    // the names of dynamic tests are only known at runtime, so they can't be
+    // selected one by one, and all of them run again
+    if (!contains(test) || itemData.get(test) instanceof TestDynamic) {
+      return [test];
+    }
+
+    return excludeTests(test.children.all, ids);
+  });
+}
+
+/**
+ * Gets whether the test, or a folder, file or suite it's in, is included in
+ * the request and not excluded from it.
+ */
+function isInRequest(test: vscode.TestItem, req: vscode.TestRunRequest) {
+  const isIn = (items: ReadonlyArray<vscode.TestItem> | undefined) => {
+    for (let item: vscode.TestItem | undefined = test; item; item = item.parent) {
+      if (items?.some(i => i.id === item!.id)) {
+        return true;
+      }
+    }
+    return false;
+  };
+
+  return (!req.include || isIn(req.include)) && !isIn(req.exclude);
 }
 
 async function getPendingTestMap(tests: ReadonlyArray<vscode.TestItem>) {
@@ -0,0 +1,69 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+// @ts-check
+
+/**
+ * Loaded in the extension host as the `--extensionTestsPath` when running
+ * tests of built-in extensions. It runs the selected files with the repo's
+ * mocha, and reports results to the test provider over a socket in the same
+ * format as the `full-json-stream` reporter used for unit tests.
+ */
+
+const { readFileSync } = require('fs');
+const { createConnection } = require('net');
+const path = require('path');
+
+/** @param {any} test */
+const clean = test => ({
+  title: test.title,
+  fullTitle: test.fullTitle(),
+  file: test.file,
+  duration: test.duration,
+  currentRetry: test.currentRetry(),
+  speed: test.speed,
+});
+
+exports.run = async () => {
+  // the config is in a file, since the selection may be too large for the environment
+  const config = JSON.parse(readFileSync(process.env.VSCODE_SELFHOST_TEST_CONFIG || '', 'utf-8'));
+  const socket = createConnection(config.port, '127.0.0.1');
+  await new Promise((resolve, reject) => socket.once('connect', resolve).once('error', reject));
+
+  /** @param {string} event @param {unknown} data */
+  const send = (event, data) => socket.write(JSON.stringify([event, data]) + '\n');
+
+  const Mocha = require(path.join(config.repo, 'node_modules', 'mocha'));
+  const mocha = new Mocha({
+    ui: 'tdd',
+    timeout: config.timeout === undefined ? 60000 : config.timeout,
+    grep: config.grep ? new RegExp(config.grep) : undefined,
+    /** @param {any} runner */
+    reporter: function (runner) {
+      runner.once('start', () => send('start', { total: runner.total }));
+      runner.on('pass', (/** @type {any} */ test) => send('pass', clean(test)));
+      runner.on('pending', (/** @type {any} */ test) => send('pending', clean(test)));
+      runner.on('fail', (/** @type {any} */ test, /** @type {any} */ err) =>
+        send('fail', {
+          ...clean(test),
+          err: String((err && err.message) || err),
+          stack: (err && err.stack) || null,
+          expected: err && err.expected,
+          actual: err && err.actual,
+        })
+      );
+      runner.once('end', () => send('end', runner.stats));
+    },
+  });
+
+  for (const file of config.files) {
+    mocha.addFile(file);
+  }
+
+  try {
+    await new Promise(resolve => mocha.run(resolve));
+  } finally {
+    await new Promise(resolve => socket.end(resolve));
+  }
+};
@@ -0,0 +1,56 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import { execFile } from 'child_process';
+import * as vscode from 'vscode';
+
+/** Branches the merge base is looked up against, in order of preference */
+const MAIN_BRANCHES = ['origin/main', 'main', 'origin/master', 'master'];
+
+const git = (folder: vscode.WorkspaceFolder, args: ReadonlyArray<string>) =>
+  new Promise<string | undefined>(resolve =>
+    execFile('git', args, { cwd: folder.uri.fsPath, maxBuffer: 16 * 1024 * 1024 }, (err, stdout) =>
+      resolve(err ? undefined : stdout)
+    )
+  );
+
+/**
+ * Gets the commit the repository is currently at, if it can be found.
+ */
+export const getHeadCommit = async (folder: vscode.WorkspaceFolder) =>
+  (await git(folder, ['rev-parse', 'HEAD']))?.trim();
+
+/**
+ * Gets the commit where HEAD branched off of the main branch.
+ */
+export const getMergeBase = async (folder: vscode.WorkspaceFolder) => {
+  for (const branch of MAIN_BRANCHES) {
+    const base = (await git(folder, ['merge-base', 'HEAD', branch]))?.trim();
+    if (base) {
+      return base;
+    }
+  }
+
+  return undefined;
+};
+
+/**
+ * Gets files in the working tree that differ from the commit, including
+ * untracked files.
+ */
+export const getChangedFiles = async (folder: vscode.WorkspaceFolder, commit = 'HEAD') => {
+  const [changed, untracked] = await Promise.all([
+    git(folder, ['diff', '--name-only', '--no-renames', commit]),
+    git(folder, ['ls-files', '--others', '--exclude-standard']),
+  ]);
+
+  if (changed === undefined) {
+    throw new Error(`Could not get changes since ${commit}`);
+  }
+
+  return `${changed}\n${untracked ?? ''}`
+    .split('\n')
+    .filter(line => !!line.trim())
+    .map(file => vscode.Uri.joinPath(folder.uri, file.trim()));
+};
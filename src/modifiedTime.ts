@@ -0,0 +1,17 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import { promises as fs } from 'fs';
+
+/**
+ * Gets when the file was last modified, in milliseconds since the epoch, or
+ * undefined if it doesn't exist.
+ */
+export const getModifiedTime = async (file: string) => {
+  try {
+    return (await fs.stat(file)).mtimeMs;
+  } catch {
+    return undefined;
+  }
+};
@@ -0,0 +1,217 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import { dirname, join } from 'path';
+import * as ts from 'typescript';
+import { TextDecoder } from 'util';
+import * as vscode from 'vscode';
+
+/** Sources whose imports are indexed */
+const SOURCE_PATTERNS = ['src/vs/**/*.ts', 'extensions/*/src/**/*.ts'];
+
+/** Number of files read at once while indexing */
+const READ_BATCH_SIZE = 50;
+
+const textDecoder = new TextDecoder('utf-8');
+
+const toKey = (uri: vscode.Uri) => uri.fsPath.toLowerCase();
+
+/**
+ * Index of which sources import each other, used to find the test files
+ * affected by changes to a file. It's built the first time it's needed,
+ * and then kept up to date as files change.
+ */
+export class ModuleGraph implements vscode.Disposable {
+  /** Files each file imports */
+  private readonly imports = new Map<string, ReadonlySet<string>>();
+  /** Files which import each file */
+  private readonly importers = new Map<string, Set<string>>();
+  private readonly uris = new Map<string, vscode.Uri>();
+  private readonly watchers: vscode.FileSystemWatcher[] = [];
+  private built?: Promise<void>;
+
+  constructor(
+    private readonly folder: vscode.WorkspaceFolder,
+    private readonly isTestFile: (uri: vscode.Uri) => boolean
+  ) {}
+
+  /**
+   * Gets the test files which are, or directly or transitively import, any of
+   * the changed files.
+   */
+  public async getAffectedTestFiles(changed: ReadonlyArray<vscode.Uri>) {
+    const affected = await this.walk(changed, this.importers);
+    return affected.filter(this.isTestFile);
+  }
+
+  /**
+   * Gets the files, along with everything they directly or transitively import.
+   * Rather than waiting for the whole index, only these files are read, if
+   * they haven't been already.
+   */
+  public async getImportedFiles(files: ReadonlyArray<vscode.Uri>) {
+    this.watch();
+    const seen = new Map<string, vscode.Uri>();
+    let next = files;
+    while (next.length) {
+      const unseen: vscode.Uri[] = [];
+      for (const uri of next) {
+        const key = toKey(uri);
+        if (!seen.has(key)) {
+          seen.set(key, uri);
+          unseen.push(uri);
+        }
+      }
+
+      const unread = unseen.filter(uri => !this.imports.has(toKey(uri)));
+      for (let i = 0; i < unread.length; i += READ_BATCH_SIZE) {
+        await Promise.all(unread.slice(i, i + READ_BATCH_SIZE).map(f => this.updateFile(f)));
+      }
+
+      next = unseen.flatMap(uri =>
+        [...(this.imports.get(toKey(uri)) ?? [])].map(key => this.uris.get(key)!)
+      );
+    }
+
+    return [...seen.values()];
+  }
+
+  /**
+   * Gets the files and everything reachable from them through the edges.
+   */
+  private async walk(
+    files: ReadonlyArray<vscode.Uri>,
+    edges: ReadonlyMap<string, ReadonlySet<string>>
+  ) {
+    if (!this.built) {
+      this.built = this.build();
+    }
+    await this.built;
+
+    const seen = new Set<string>();
+    const queue: string[] = [];
+    for (const uri of files) {
+      const key = toKey(uri);
+      this.uris.set(key, uri);
+      queue.push(key);
+    }
+
+    while (queue.length) {
+      const key = queue.pop()!;
+      if (seen.has(key)) {
+        continue;
+      }
+
+      seen.add(key);
+      queue.push(...(edges.get(key) ?? []));
+    }
+
+    return [...seen].map(key => this.uris.get(key)!);
+  }
+
+  /**
+   * @override
+   */
+  public dispose() {
+    this.watchers.forEach(w => w.dispose());
+  }
+
+  /**
+   * Keeps files that have been read up to date as they change.
+   */
+  private watch() {
+    if (this.watchers.length) {
+      return;
+    }
+
+    for (const glob of SOURCE_PATTERNS) {
+      const watcher = vscode.workspace.createFileSystemWatcher(
+        new vscode.RelativePattern(this.folder, glob)
+      );
+      watcher.onDidCreate(uri => this.updateFile(uri));
+      watcher.onDidChange(uri => this.updateFile(uri));
+      watcher.onDidDelete(uri => this.setImports(uri, []));
+      this.watchers.push(watcher);
+    }
+  }
+
+  private build() {
+    this.watch();
+    return vscode.window.withProgress(
+      { location: vscode.ProgressLocation.Window, title: 'Indexing test dependencies' },
+      async () => {
+        for (const glob of SOURCE_PATTERNS) {
+          const pattern = new vscode.RelativePattern(this.folder, glob);
+          const files = await vscode.workspace.findFiles(pattern);
+          for (let i = 0; i < files.length; i += READ_BATCH_SIZE) {
+            await Promise.all(files.slice(i, i + READ_BATCH_SIZE).map(f => this.updateFile(f)));
+          }
+        }
+      }
+    );
+  }
+
+  private async updateFile(uri: vscode.Uri) {
+    let content: string;
+    try {
+      content = textDecoder.decode(await vscode.workspace.fs.readFile(uri));
+    } catch {
+      // imports that aren't TypeScript sources in the repo, like `.d.ts` files
+      this.setImports(uri, []);
+      return;
+    }
+
+    const imports: vscode.Uri[] = [];
+    for (const { fileName } of ts.preProcessFile(content, true, true).importedFiles) {
+      const resolved = this.resolveImport(uri, fileName);
+      if (resolved) {
+        imports.push(resolved);
+      }
+    }
+
+    this.setImports(uri, imports);
+  }
+
+  /**
+   * Resolves an import to the TypeScript file it refers to. VS Code sources
+   * import each other by `vs/...` paths, or by relative paths. Imports of
+   * other modules aren't part of the graph.
+   */
+  private resolveImport(from: vscode.Uri, specifier: string) {
+    let path: string;
+    if (specifier.startsWith('vs/')) {
+      path = join(this.folder.uri.fsPath, 'src', specifier);
+    } else if (specifier.startsWith('.')) {
+      path = join(dirname(from.fsPath), specifier);
+    } else {
+      return undefined;
+    }
+
+    return vscode.Uri.file(path.replace(/\.js$/, '') + '.ts');
+  }
+
+  private setImports(uri: vscode.Uri, imports: ReadonlyArray<vscode.Uri>) {
+    const key = toKey(uri);
+    for (const previous of this.imports.get(key) ?? []) {
+      this.importers.get(previous)?.delete(key);
+    }
+
+    const keys = new Set<string>();
+    for (const imported of imports) {
+      const importedKey = toKey(imported);
+      keys.add(importedKey);
+      this.uris.set(importedKey, imported);
+
+      let importers = this.importers.get(importedKey);
+      if (!importers) {
+        importers = new Set();
+        this.importers.set(importedKey, importers);
+      }
+      importers.add(key);
+    }
+
+    this.uris.set(key, uri);
+    this.imports.set(key, keys);
+  }
+}
@@ -0,0 +1,238 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import * as vscode from 'vscode';
+
+/** Key the options of all profiles are stored under in workspace state */
+const STORAGE_KEY = 'profileOptions';
+
+/**
+ * Options the user configured for a run profile.
+ */
+export interface IProfileOptions {
+  /** Arguments added to the test script's own */
+  args: string[];
+  /** Variables added to the test process's environment */
+  env: { [key: string]: string };
+  /** Timeout of each test in milliseconds, or undefined for the script's default */
+  timeout?: number;
+  /** Whether browsers are shown while tests run, rather than headless */
+  headed: boolean;
+  /** Times failed tests are retried, or undefined to use the `retries` setting */
+  retries?: number;
+  /** Whether the test script collects coverage */
+  coverage: boolean;
+  /** Options for the mocha reporter, as `key=value` pairs */
+  reporterOptions: string[];
+}
+
+export const DEFAULT_PROFILE_OPTIONS: Readonly<IProfileOptions> = {
+  args: [],
+  env: {},
+  headed: false,
+  coverage: false,
+  reporterOptions: [],
+};
+
+/**
+ * Splits the text on whitespace, except inside double quotes.
+ */
+const splitWords = (text: string) =>
+  (text.match(/"[^"]*"|\S+/g) ?? []).map(w => w.replace(/^"(.*)"$/, '$1'));
+
+/**
+ * Joins words so they're split back the same way by {@link splitWords}.
+ */
+const joinWords = (words: ReadonlyArray<string>) =>
+  words.map(w => (/\s/.test(w) ? `"${w}"` : w)).join(' ');
+
+const formatEnv = (env: { [key: string]: string }) =>
+  joinWords(Object.entries(env).map(([key, value]) => `${key}=${value}`));
+
+const parseEnv = (text: string) =>
+  Object.fromEntries(
+    splitWords(text).map(pair => {
+      const i = pair.indexOf('=');
+      return [pair.slice(0, i), pair.slice(i + 1)];
+    })
+  );
+
+const validateEnv = (text: string) =>
+  splitWords(text).every(pair => pair.indexOf('=') > 0)
+    ? undefined
+    : 'Give each variable as KEY=value';
+
+const formatCount = (count: number | undefined) => (count === undefined ? '' : String(count));
+
+const parseCount = (text: string) => (text.trim() ? Number(text) : undefined);
+
+const validateCount = (text: string) =>
+  !text.trim() || /^\d+$/.test(text.trim()) ? undefined : 'Enter a whole number, or nothing';
+
+/**
+ * Options of each run profile, saved in the workspace.
+ */
+export class ProfileOptionsStore {
+  constructor(private readonly memento: vscode.Memento) {}
+
+  /**
+   * Gets the options of the profile with the label.
+   */
+  public get(profile: string): IProfileOptions {
+    const stored = this.memento.get<{ [profile: string]: Partial<IProfileOptions> }>(
+      STORAGE_KEY,
+      {}
+    );
+    return { ...DEFAULT_PROFILE_OPTIONS, ...stored[profile] };
+  }
+
+  /**
+   * Saves the options of the profile with the label.
+   */
+  public async set(profile: string, options: IProfileOptions) {
+    const stored = { ...this.memento.get(STORAGE_KEY, {}), [profile]: options };
+    await this.memento.update(STORAGE_KEY, stored);
+  }
+}
+
+/**
+ * Options that only some runners support, and so are only offered in the
+ * profiles of those runners.
+ */
+export interface IProfileFeatures {
+  /** Whether browsers can be shown while tests run */
+  headed: boolean;
+  /** Whether the test script can collect coverage */
+  coverage: boolean;
+}
+
+interface IOptionItem extends vscode.QuickPickItem {
+  /** Asks for the option's new value, resolving to undefined if the user cancels */
+  edit(): Thenable<Partial<IProfileOptions> | undefined>;
+}
+
+/**
+ * Asks for a new value of a text option, resolving to undefined if the
+ * user cancels.
+ */
+const editText = async <T>(
+  prompt: string,
+  value: string,
+  parse: (text: string) => T,
+  validateInput?: (text: string) => string | undefined
+) => {
+  const text = await vscode.window.showInputBox({ prompt, value, validateInput });
+  return text === undefined ? undefined : parse(text);
+};
+
+const getOptionItems = (options: IProfileOptions, features: IProfileFeatures): IOptionItem[] => {
+  const items: IOptionItem[] = [
+    {
+      label: 'Arguments',
+      description: joinWords(options.args) || 'None',
+      detail: 'Extra arguments for the test script',
+      edit: () =>
+        editText('Arguments, separated by spaces', joinWords(options.args), text => ({
+          args: splitWords(text),
+        })),
+    },
+    {
+      label: 'Environment Variables',
+      description: formatEnv(options.env) || 'None',
+      detail: 'Extra variables for the test process',
+      edit: () =>
+        editText(
+          'Variables as KEY=value, separated by spaces',
+          formatEnv(options.env),
+          text => ({ env: parseEnv(text) }),
+          validateEnv
+        ),
+    },
+    {
+      label: 'Timeout',
+      description: options.timeout === undefined ? 'Default' : `${options.timeout} ms`,
+      detail: 'Milliseconds each test can take before it fails',
+      edit: () =>
+        editText(
+          'Timeout in milliseconds, or nothing for the default',
+          formatCount(options.timeout),
+          text => ({ timeout: parseCount(text) }),
+          validateCount
+        ),
+    },
+    {
+      label: 'Retries',
+      description: options.retries === undefined ? 'From settings' : String(options.retries),
+      detail: 'Times failed tests are retried in a new process',
+      edit: () =>
+        editText(
+          'Number of retries, or nothing to use the setting',
+          formatCount(options.retries),
+          text => ({ retries: parseCount(text) }),
+          validateCount
+        ),
+    },
+    {
+      label: 'Reporter Options',
+      description: joinWords(options.reporterOptions) || 'None',
+      detail: 'Options for the mocha reporter',
+      edit: () =>
+        editText(
+          'Options as key=value, separated by spaces',
+          joinWords(options.reporterOptions),
+          text => ({ reporterOptions: splitWords(text) })
+        ),
+    },
+  ];
+
+  if (features.coverage) {
+    items.push({
+      label: 'Coverage',
+      description: options.coverage ? 'On' : 'Off',
+      detail: 'Collect coverage while tests run',
+      edit: async () => ({ coverage: !options.coverage }),
+    });
+  }
+
+  if (features.headed) {
+    items.push({
+      label: 'Show Browser',
+      description: options.headed ? 'On' : 'Off',
+      detail: 'Show the browser while tests run, rather than running it headless',
+      edit: async () => ({ headed: !options.headed }),
+    });
+  }
+
+  items.push({
+    label: 'Reset to Defaults',
+    edit: async () => ({ ...DEFAULT_PROFILE_OPTIONS, timeout: undefined, retries: undefined }),
+  });
+
+  return items;
+};
+
+/**
+ * Shows the options of the profile, and lets the user change them one at a
+ * time until they close the picker.
+ */
+export async function configureProfile(
+  store: ProfileOptionsStore,
+  profile: string,
+  features: IProfileFeatures
+) {
+  while (true) {
+    const options = store.get(profile);
+    const item = await vscode.window.showQuickPick(getOptionItems(options, features), {
+      placeHolder: `Configure "${profile}"`,
+    });
+    if (!item) {
+      return;
+    }
+
+    const changes = await item.edit();
+    if (changes) {
+      await store.set(profile, { ...options, ...changes });
+    }
+  }
+}
@@ -0,0 +1,102 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import * as vscode from 'vscode';
+import { TreeGrouping } from './testTree';
+
+export const CONFIG_SECTION = 'selfhost-test-provider';
+
+/** Test scripts of the VS Code repo, by the environment they run tests in */
+export interface ITestScripts {
+  electron: string;
+  browser: string;
+  node: string;
+}
+
+const getConfig = (scope?: vscode.ConfigurationScope) =>
+  vscode.workspace.getConfiguration(CONFIG_SECTION, scope);
+
+/**
+ * Gets globs, relative to the workspace folder, of files tests are found in.
+ */
+export const getTestFilePatterns = () => getConfig().get<string[]>('discovery.include')!;
+
+/**
+ * Gets names of the functions that declare suites, flaky suites and tests.
+ */
+export const getTestFunctionNames = () => ({
+  suites: getConfig().get<string[]>('discovery.suiteFunctions')!,
+  flakySuites: getConfig().get<string[]>('discovery.flakySuiteFunctions')!,
+  tests: getConfig().get<string[]>('discovery.testFunctions')!,
+});
+
+/**
+ * Gets the path of the script that runs tests in the environment, relative
+ * to the repo.
+ */
+export const getTestScript = (folder: vscode.WorkspaceFolder, environment: keyof ITestScripts) =>
+  getConfig(folder).get<ITestScripts>('testScripts')![environment];
+
+/**
+ * Gets arguments added to every run of a test script.
+ */
+export const getDefaultArgs = (folder: vscode.WorkspaceFolder) =>
+  getConfig(folder).get<string[]>('args')!;
+
+/**
+ * Gets variables added to the environment of every test process.
+ */
+export const getDefaultEnv = (folder: vscode.WorkspaceFolder) =>
+  getConfig(folder).get<{ [key: string]: string }>('env')!;
+
+/**
+ * Gets the timeout of each test in milliseconds, if one is configured.
+ */
+export const getDefaultTimeout = (folder: vscode.WorkspaceFolder) =>
+  getConfig(folder).get<number | null>('timeout') ?? undefined;
+
+/**
+ * Gets the directory, relative to the workspace folder, that reports of
+ * each run are written to. Reports aren't written if it's empty.
+ */
+export const getReportDirectory = (folder: vscode.WorkspaceFolder) =>
+  getConfig(folder).get<string>('reportDirectory')!.trim();
+
+/**
+ * Gets the Playwright browsers that tests can be run in.
+ */
+export const getBrowsers = () => getConfig().get<string[]>('browsers')!;
+
+/**
+ * Gets how many test processes can run at once.
+ */
+export const getMaxParallelProcesses = () =>
+  Math.max(1, getConfig().get<number>('maxParallelProcesses')!);
+
+/**
+ * Gets how many times failed tests are retried.
+ */
+export const getRetryCount = () => Math.max(0, getConfig().get<number>('retries')!);
+
+/**
+ * Gets whether tests that failed in the last run are run before the others.
+ */
+export const getRunFailedFirst = () => getConfig().get<boolean>('runFailedFirst')!;
+
+/**
+ * Gets how test files are grouped in the tree.
+ */
+export const getTreeGrouping = () => getConfig().get<TreeGrouping>('treeGrouping')!;
+
+/**
+ * Gets whether the file in the folder matches the test file globs.
+ */
+export const isTestFile = (folder: vscode.WorkspaceFolder, uri: vscode.Uri) => {
+  // only the URI is used to match a selector with a pattern and no language
+  const document = { uri } as vscode.TextDocument;
+  return getTestFilePatterns().some(
+    glob =>
+      vscode.languages.match({ pattern: new vscode.RelativePattern(folder, glob) }, document) > 0
+  );
+};
@@ -4,19 +4,368 @@
 
 import * as ts from 'typescript';
 import * as vscode from 'vscode';
-import { TestCase, TestConstruct, TestSuite, VSCodeTest } from './testTree';
+import {
+  TestCase,
+  TestConstruct,
+  TestDynamic,
+  TestModifier,
+  TestSuite,
+  VSCodeTest,
+} from './testTree';
 
-const suiteNames = new Set(['suite', 'flakySuite']);
+let suiteNames: ReadonlySet<string> = new Set(['suite']);
+let flakySuiteNames: ReadonlySet<string> = new Set(['flakySuite']);
+let testNames: ReadonlySet<string> = new Set(['test']);
 
-export const extractTestFromNode = (src: ts.SourceFile, node: ts.Node, parent: VSCodeTest) => {
+/**
+ * Sets the names of the functions that declare suites and tests. Suites
+ * declared by the flaky suite functions have their tests retried.
+ */
+export const setTestFunctionNames = (
+  suites: Iterable<string>,
+  flakySuites: Iterable<string>,
+  tests: Iterable<string>
+) => {
+  suiteNames = new Set(suites);
+  flakySuiteNames = new Set(flakySuites);
+  testNames = new Set(tests);
+};
+
+const modifierNames = new Set<string>([TestModifier.Skip, TestModifier.Only]);
+
+/**
+ * A value that can be computed from the source without running it.
+ */
+export type ConstantValue =
+  | string
+  | number
+  | boolean
+  | null
+  | ConstantValue[]
+  | { [key: string]: ConstantValue };
+
+/**
+ * Constant bindings visible at a point in the file. A binding set to
+ * `undefined` shadows an outer one with a value only known at runtime.
+ */
+export type Scope = Map<string, ConstantValue | undefined>;
+
+const isRecord = (value: unknown): value is { [key: string]: ConstantValue } =>
+  typeof value === 'object' && value !== null && !Array.isArray(value);
+
+const getPropertyName = (name: ts.PropertyName) =>
+  ts.isIdentifier(name) || ts.isStringLiteral(name) || ts.isNumericLiteral(name)
+    ? name.text
+    : undefined;
+
+/**
+ * Tries to statically evaluate the expression, returning undefined if its
+ * value depends on something only known at runtime.
+ */
+export const evaluateConstant = (
+  node: ts.Expression,
+  scope: Scope
+): ConstantValue | undefined => {
+  if (ts.isStringLiteralLike(node)) {
+    return node.text;
+  }
+
+  if (ts.isNumericLiteral(node)) {
+    return Number(node.text);
+  }
+
+  switch (node.kind) {
+    case ts.SyntaxKind.TrueKeyword:
+      return true;
+    case ts.SyntaxKind.FalseKeyword:
+      return false;
+    case ts.SyntaxKind.NullKeyword:
+      return null;
+  }
+
+  if (ts.isParenthesizedExpression(node) || ts.isAsExpression(node) || ts.isTypeAssertion(node)) {
+    return evaluateConstant(node.expression, scope);
+  }
+
+  if (ts.isIdentifier(node)) {
+    return scope.get(node.text);
+  }
+
+  if (ts.isTemplateExpression(node)) {
+    let str = node.head.text;
+    for (const span of node.templateSpans) {
+      const value = evaluateConstant(span.expression, scope);
+      if (value === undefined) {
+        return undefined;
+      }
+      str += String(value) + span.literal.text;
+    }
+    return str;
+  }
+
+  if (ts.isBinaryExpression(node) && node.operatorToken.kind === ts.SyntaxKind.PlusToken) {
+    const left = evaluateConstant(node.left, scope);
+    const right = evaluateConstant(node.right, scope);
+    if (left === undefined || right === undefined) {
+      return undefined;
+    }
+
+    return typeof left === 'number' && typeof right === 'number'
+      ? left + right
+      : String(left) + String(right);
+  }
+
+  if (ts.isArrayLiteralExpression(node)) {
+    const values: ConstantValue[] = [];
+    for (const element of node.elements) {
+      const value = evaluateConstant(
+        ts.isSpreadElement(element) ? element.expression : element,
+        scope
+      );
+      if (value === undefined) {
+        return undefined;
+      } else if (ts.isSpreadElement(element)) {
+        if (!Array.isArray(value)) {
+          return undefined;
+        }
+        values.push(...value);
+      } else {
+        values.push(value);
+      }
+    }
+    return values;
+  }
+
+  if (ts.isObjectLiteralExpression(node)) {
+    // Properties we can't evaluate are left out, so only names that read
+    // them end up being dynamic.
+    const obj: { [key: string]: ConstantValue } = {};
+    for (const prop of node.properties) {
+      let key: string | undefined;
+      let value: ConstantValue | undefined;
+      if (ts.isPropertyAssignment(prop)) {
+        key = getPropertyName(prop.name);
+        value = evaluateConstant(prop.initializer, scope);
+      } else if (ts.isShorthandPropertyAssignment(prop)) {
+        key = prop.name.text;
+        value = scope.get(key);
+      }
+
+      if (key !== undefined && value !== undefined) {
+        obj[key] = value;
+      }
+    }
+    return obj;
+  }
+
+  if (ts.isPropertyAccessExpression(node)) {
+    const target = evaluateConstant(node.expression, scope);
+    const key = node.name.text;
+    if (key === 'length' && (typeof target === 'string' || Array.isArray(target))) {
+      return target.length;
+    }
+
+    return isRecord(target) && Object.prototype.hasOwnProperty.call(target, key)
+      ? target[key]
+      : undefined;
+  }
+
+  if (ts.isElementAccessExpression(node)) {
+    const target = evaluateConstant(node.expression, scope);
+    const index = evaluateConstant(node.argumentExpression, scope);
+    if (Array.isArray(target) && typeof index === 'number') {
+      return target[index];
+    }
+
+    return isRecord(target) &&
+      typeof index === 'string' &&
+      Object.prototype.hasOwnProperty.call(target, index)
+      ? target[index]
+      : undefined;
+  }
+
+  return undefined;
+};
+
+/**
+ * Binds the names in the binding to the value, or to `undefined` for any
+ * part of the value that isn't known.
+ */
+const bindName = (name: ts.BindingName, value: ConstantValue | undefined, scope: Scope) => {
+  if (ts.isIdentifier(name)) {
+    scope.set(name.text, value);
+  } else if (ts.isObjectBindingPattern(name)) {
+    for (const element of name.elements) {
+      const key = element.propertyName
+        ? getPropertyName(element.propertyName)
+        : ts.isIdentifier(element.name)
+        ? element.name.text
+        : undefined;
+      const property =
+        !element.dotDotDotToken &&
+        key !== undefined &&
+        isRecord(value) &&
+        Object.prototype.hasOwnProperty.call(value, key)
+          ? value[key]
+          : undefined;
+      bindName(element.name, property, scope);
+    }
+  } else {
+    name.elements.forEach((element, i) => {
+      if (!ts.isOmittedExpression(element)) {
+        const item = !element.dotDotDotToken && Array.isArray(value) ? value[i] : undefined;
+        bindName(element.name, item, scope);
+      }
+    });
+  }
+};
+
+/**
+ * Adds any variables declared by the node to the scope. Only `const`
+ * declarations are given values, other declarations shadow outer constants.
+ */
+export const declareVariables = (node: ts.Node, scope: Scope) => {
+  if (!ts.isVariableStatement(node)) {
+    return;
+  }
+
+  const isConst = !!(node.declarationList.flags & ts.NodeFlags.Const);
+  for (const decl of node.declarationList.declarations) {
+    const value =
+      isConst && decl.initializer ? evaluateConstant(decl.initializer, scope) : undefined;
+    bindName(decl.name, value, scope);
+  }
+};
+
+/**
+ * Creates a child scope for the node's body, if it introduces one.
+ */
+export const enterScope = (node: ts.Node, scope: Scope): Scope => {
+  if (ts.isFunctionLike(node)) {
+    const inner = new Map(scope);
+    for (const param of node.parameters) {
+      bindName(param.name, undefined, inner);
+    }
+    return inner;
+  }
+
+  return ts.isBlock(node) ? new Map(scope) : scope;
+};
+
+export interface ILoopIterations {
+  /** Body of the loop that's run for each iteration */
+  body: ts.Node;
+  /** Scope for each iteration of the loop, or a single scope if they're unknown */
+  scopes: Scope[];
+}
+
+const getIterationScopes = (
+  scope: Scope,
+  values: ConstantValue | undefined,
+  bind: (scope: Scope, value?: ConstantValue, index?: number) => void
+) => {
+  if (!Array.isArray(values)) {
+    const inner = new Map(scope);
+    bind(inner);
+    return [inner];
+  }
+
+  return values.map((value, i) => {
+    const inner = new Map(scope);
+    bind(inner, value, i);
+    return inner;
+  });
+};
+
+/**
+ * Gets the iterations of a `for..of` loop or `.forEach()` call, so tests
+ * declared in them can be expanded when the iterated array is constant.
+ */
+export const getLoopIterations = (node: ts.Node, scope: Scope): ILoopIterations | undefined => {
+  if (
+    ts.isForOfStatement(node) &&
+    ts.isVariableDeclarationList(node.initializer) &&
+    node.initializer.declarations.length === 1
+  ) {
+    const binding = node.initializer.declarations[0].name;
+    return {
+      body: node.statement,
+      scopes: getIterationScopes(scope, evaluateConstant(node.expression, scope), (s, v) =>
+        bindName(binding, v, s)
+      ),
+    };
+  }
+
+  if (
+    ts.isCallExpression(node) &&
+    ts.isPropertyAccessExpression(node.expression) &&
+    node.expression.name.text === 'forEach' &&
+    node.arguments.length > 0
+  ) {
+    const fn = node.arguments[0];
+    if (!ts.isArrowFunction(fn) && !ts.isFunctionExpression(fn)) {
+      return undefined;
+    }
+
+    const [itemParam, indexParam] = fn.parameters;
+    return {
+      body: fn.body,
+      scopes: getIterationScopes(
+        scope,
+        evaluateConstant(node.expression.expression, scope),
+        (s, v, i) => {
+          if (itemParam) {
+            bindName(itemParam.name, v, s);
+          }
+          if (indexParam) {
+            bindName(indexParam.name, i, s);
+          }
+        }
+      ),
+    };
+  }
+
+  return undefined;
+};
+
+/**
+ * Gets the name of the test function called in the expression, and its
+ * modifier if it was called like `test.skip(...)` or `suite.only(...)`.
+ */
+const getCallee = (lhs: ts.Expression): [name: string, modifier?: TestModifier] | undefined => {
+  if (ts.isIdentifier(lhs)) {
+    return [lhs.escapedText.toString()];
+  }
+
+  if (
+    ts.isPropertyAccessExpression(lhs) &&
+    ts.isIdentifier(lhs.expression) &&
+    ts.isIdentifier(lhs.name) &&
+    modifierNames.has(lhs.name.escapedText.toString())
+  ) {
+    return [
+      lhs.expression.escapedText.toString(),
+      lhs.name.escapedText.toString() as TestModifier,
+    ];
+  }
+
+  return undefined;
+};
+
+export const extractTestFromNode = (
+  src: ts.SourceFile,
+  node: ts.Node,
+  parent: VSCodeTest,
+  scope: Scope
+) => {
   if (!ts.isCallExpression(node)) {
     return undefined;
   }
 
-  const lhs = node.expression;
+  const callee = getCallee(node.expression);
   const name = node.arguments[0];
   const func = node.arguments[1];
-  if (!name || !ts.isIdentifier(lhs) || !ts.isStringLiteralLike(name)) {
+  if (!name || !callee) {
     return undefined;
   }
 
@@ -24,6 +373,13 @@ export const extractTestFromNode = (src: ts.SourceFile, node: ts.Node, parent: V
     return undefined;
   }
 
+  const [fnName, modifier] = callee;
+  const isFlaky = flakySuiteNames.has(fnName);
+  const isSuite = isFlaky || suiteNames.has(fnName);
+  if (!testNames.has(fnName) && !isSuite) {
+    return undefined;
+  }
+
   const start = src.getLineAndCharacterOfPosition(name.pos);
   const end = src.getLineAndCharacterOfPosition(func.end);
   const range = new vscode.Range(
@@ -32,13 +388,12 @@ export const extractTestFromNode = (src: ts.SourceFile, node: ts.Node, parent: V
   );
 
   const cparent = parent instanceof TestConstruct ? parent : undefined;
-  if (lhs.escapedText === 'test') {
-    return new TestCase(name.text, range, cparent);
-  }
-
-  if (suiteNames.has(lhs.escapedText.toString())) {
-    return new TestSuite(name.text, range, cparent);
+  const value = evaluateConstant(name, scope);
+  if (value === undefined) {
+    return new TestDynamic(range, cparent, modifier);
   }
 
-  return undefined;
+  return isSuite
+    ? new TestSuite(String(value), range, cparent, modifier, isFlaky)
+    : new TestCase(String(value), range, cparent, modifier);
 };
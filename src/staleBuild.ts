@@ -0,0 +1,116 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import * as vscode from 'vscode';
+import { getModifiedTime } from './modifiedTime';
+import { runShellTask } from './tasks';
+import { getCompiledPathForSourceFile } from './testTree';
+
+/** How often stale sources are checked again while waiting for the build */
+const BUILD_POLL_INTERVAL = 1000;
+
+/** Command that compiles the repo */
+const COMPILE_COMMAND = 'yarn compile';
+
+export const enum BuildState {
+  /** Compiled output is up to date with the sources */
+  Fresh,
+  /** The user chose to run tests against out-of-date output */
+  Stale,
+  /** The user cancelled the run */
+  Cancelled,
+}
+
+/**
+ * Gets the TypeScript sources which were changed after they were last
+ * compiled, or haven't been compiled at all.
+ */
+export const getStaleSources = async (sources: ReadonlyArray<vscode.Uri>) => {
+  const stale: vscode.Uri[] = [];
+  await Promise.all(
+    sources.map(async uri => {
+      if (!uri.fsPath.endsWith('.ts') || uri.fsPath.endsWith('.d.ts')) {
+        return;
+      }
+
+      const [source, compiled] = await Promise.all([
+        getModifiedTime(uri.fsPath),
+        getModifiedTime(getCompiledPathForSourceFile(uri.fsPath)),
+      ]);
+      if (source !== undefined && (compiled === undefined || source > compiled)) {
+        stale.push(uri);
+      }
+    })
+  );
+
+  return stale;
+};
+
+/**
+ * Checks that compiled output of the sources is up to date. If it's not,
+ * asks the user whether to wait for the watch build, compile, or run anyway.
+ * If `prompt` is false, stale output is run without asking.
+ */
+export async function ensureFreshBuild(
+  folder: vscode.WorkspaceFolder,
+  sources: ReadonlyArray<vscode.Uri>,
+  prompt = true
+): Promise<BuildState> {
+  let stale = await getStaleSources(sources);
+  if (!stale.length) {
+    return BuildState.Fresh;
+  } else if (!prompt) {
+    return BuildState.Stale;
+  }
+
+  const waitForBuild = 'Wait for Build';
+  const compile = 'Compile';
+  const runAnyway = 'Run Anyway';
+  const files = stale.slice(0, 10).map(uri => vscode.workspace.asRelativePath(uri));
+  const choice = await vscode.window.showWarningMessage(
+    `${stale.length} source file(s) changed since they were last compiled.`,
+    {
+      modal: true,
+      detail: `Tests would run against stale output. Changed files include:\n\n${files.join('\n')}`,
+    },
+    waitForBuild,
+    compile,
+    runAnyway
+  );
+
+  switch (choice) {
+    case waitForBuild:
+      stale = await waitForCompiledOutput(stale);
+      return stale.length ? BuildState.Cancelled : BuildState.Fresh;
+    case compile:
+      return (await runShellTask(folder, 'Compile', COMPILE_COMMAND))
+        ? BuildState.Fresh
+        : BuildState.Cancelled;
+    case runAnyway:
+      return BuildState.Stale;
+    default:
+      return BuildState.Cancelled;
+  }
+}
+
+/**
+ * Waits until the stale sources have been compiled, returning any that are
+ * still stale if the user gave up waiting.
+ */
+const waitForCompiledOutput = (stale: vscode.Uri[]) =>
+  vscode.window.withProgress(
+    {
+      location: vscode.ProgressLocation.Notification,
+      title: 'Waiting for the build to finish...',
+      cancellable: true,
+    },
+    async (_progress, token) => {
+      while (stale.length && !token.isCancellationRequested) {
+        await new Promise(resolve => setTimeout(resolve, BUILD_POLL_INTERVAL));
+        stale = await getStaleSources(stale);
+      }
+
+      return stale;
+    }
+  );
@@ -0,0 +1,32 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import * as vscode from 'vscode';
+
+/**
+ * Runs the command in a task in the terminal, returning whether it succeeded.
+ */
+export const runShellTask = async (
+  folder: vscode.WorkspaceFolder,
+  name: string,
+  command: string
+) => {
+  const task = new vscode.Task(
+    { type: 'shell' },
+    folder,
+    name,
+    'selfhost-test-provider',
+    new vscode.ShellExecution(command)
+  );
+
+  const execution = await vscode.tasks.executeTask(task);
+  return new Promise<boolean>(resolve => {
+    const listener = vscode.tasks.onDidEndTaskProcess(e => {
+      if (e.execution === execution) {
+        listener.dispose();
+        resolve(e.exitCode === 0);
+      }
+    });
+  });
+};
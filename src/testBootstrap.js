@@ -0,0 +1,89 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+// @ts-check
+
+/**
+ * Started in place of a test script when tests are selected, or browser
+ * tests are debugged. Selections can be too long for the command line, so
+ * their arguments are read from a file and added to the script's own
+ * arguments before it's loaded.
+ *
+ * When debugging browser tests, Playwright is patched so the Chromium it
+ * launches can be attached to, and so pages wait for the debugger before
+ * loading tests.
+ *
+ * Usage: testBootstrap.js <config file> <script> [...script arguments]
+ */
+
+const fs = require('fs');
+const net = require('net');
+const path = require('path');
+
+/**
+ * @typedef {{ port: number; waitServerPort: number }} IDebugBrowserConfig
+ * @typedef {{ args: string[]; debugBrowser?: IDebugBrowserConfig }} IBootstrapConfig
+ */
+
+const [argv0, , configFile, script, ...args] = process.argv;
+/** @type {IBootstrapConfig} */
+const config = JSON.parse(fs.readFileSync(configFile, 'utf-8'));
+
+/**
+ * Resolves once the wait server closes the connection, which it does once
+ * the debugger has set breakpoints.
+ * @param {number} port
+ */
+const waitForDebugger = port =>
+  new Promise(resolve => {
+    const socket = net.connect(port, '127.0.0.1');
+    socket.on('error', resolve);
+    socket.on('close', resolve);
+  });
+
+/**
+ * Makes Chromium launched by the script's Playwright listen for the
+ * debugger on the port, and its pages wait for the debugger when created.
+ * @param {IDebugBrowserConfig} debug
+ */
+const patchPlaywright = ({ port, waitServerPort }) => {
+  const playwright = require(require.resolve('playwright', {
+    paths: [path.dirname(path.resolve(script))],
+  }));
+
+  /** @param {any} target */
+  const patchNewPage = target => {
+    const newPage = target.newPage;
+    target.newPage = async (/** @type {unknown[]} */ ...newPageArgs) => {
+      const page = await newPage.apply(target, newPageArgs);
+      await waitForDebugger(waitServerPort);
+      return page;
+    };
+  };
+
+  const launch = playwright.chromium.launch;
+  playwright.chromium.launch = async (/** @type {any} */ options = {}) => {
+    const browser = await launch.call(playwright.chromium, {
+      ...options,
+      args: [...(options.args || []), `--remote-debugging-port=${port}`],
+    });
+
+    const newContext = browser.newContext;
+    browser.newContext = async (/** @type {unknown[]} */ ...contextArgs) => {
+      const context = await newContext.apply(browser, contextArgs);
+      patchNewPage(context);
+      return context;
+    };
+    patchNewPage(browser);
+
+    return browser;
+  };
+};
+
+if (config.debugBrowser) {
+  patchPlaywright(config.debugBrowser);
+}
+
+process.argv = [argv0, path.resolve(script), ...args, ...config.args];
+require(process.argv[1]);
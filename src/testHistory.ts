@@ -0,0 +1,209 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import { TextDecoder, TextEncoder } from 'util';
+import * as vscode from 'vscode';
+import { debounce } from './debounce';
+
+const HISTORY_FILE_NAME = 'history.json';
+const HISTORY_VERSION = 2;
+
+/** Number of results kept for each test */
+const MAX_RESULTS_PER_TEST = 10;
+/** Number of runs, tests and files kept, least recent ones are dropped first */
+const MAX_RUNS = 200;
+const MAX_TESTS = 10000;
+const MAX_FILES = 5000;
+
+const SAVE_DELAY = 2000;
+
+export interface ITestHistoryEntry {
+  state: vscode.TestResultState;
+  /** Duration of the test in milliseconds, if it ran */
+  duration?: number;
+  /** ID of the run the result is from, given by {@link TestHistory.startRun} */
+  run: number;
+  /** Whether the test only passed after being retried */
+  flaky?: boolean;
+}
+
+interface ISerializedHistory {
+  version: number;
+  runs: [run: number, commit: string | undefined][];
+  tests: [id: string, entries: ITestHistoryEntry[]][];
+  files: [uri: string, duration: number][];
+}
+
+/**
+ * Stores recent results of each test, and how long test files took to run,
+ * in the extension's global storage so they're kept across sessions.
+ */
+export class TestHistory implements vscode.Disposable {
+  /** Commit the repo was at in each run, by run ID */
+  private readonly runs = new Map<number, string | undefined>();
+  private readonly tests = new Map<string, ITestHistoryEntry[]>();
+  private readonly files = new Map<string, number>();
+  private readonly scheduleSave = debounce(SAVE_DELAY, () => this.save());
+  private dirty = false;
+  /** Save that's being written, if any */
+  private saving: Promise<void> = Promise.resolve();
+
+  /**
+   * Loads history from the storage folder, or starts an empty one if there
+   * is none or it can't be read.
+   */
+  public static async load(storageUri: vscode.Uri) {
+    const history = new TestHistory(storageUri);
+    try {
+      const contents = await vscode.workspace.fs.readFile(history.fileUri);
+      const data: ISerializedHistory = JSON.parse(new TextDecoder().decode(contents));
+      if (data.version === HISTORY_VERSION) {
+        data.runs.forEach(([run, commit]) => history.runs.set(run, commit));
+        data.tests.forEach(([id, entries]) => history.tests.set(id, entries));
+        data.files.forEach(([uri, duration]) => history.files.set(uri, duration));
+      }
+    } catch {
+      // no history yet
+    }
+
+    return history;
+  }
+
+  private constructor(private readonly storageUri: vscode.Uri) {}
+
+  private get fileUri() {
+    return vscode.Uri.joinPath(this.storageUri, HISTORY_FILE_NAME);
+  }
+
+  /**
+   * How long each test file took the last time it ran, by file URI.
+   */
+  public get fileDurations(): ReadonlyMap<string, number> {
+    return this.files;
+  }
+
+  /**
+   * Gets recent results of the test, oldest first.
+   */
+  public getResults(testId: string): ReadonlyArray<ITestHistoryEntry> {
+    return this.tests.get(testId) ?? [];
+  }
+
+  /**
+   * Gets the fraction of the test's recent passing or failing runs in which it
+   * was flaky, or undefined if it hasn't run.
+   */
+  public getFlakiness(testId: string) {
+    const ran = this.getResults(testId).filter(
+      e => e.state === vscode.TestResultState.Passed || e.state === vscode.TestResultState.Failed
+    );
+    return ran.length ? ran.filter(e => e.flaky).length / ran.length : undefined;
+  }
+
+  /**
+   * Starts recording a run at the commit, returning an ID for its results.
+   * IDs are the time the run started, in milliseconds since the epoch.
+   */
+  public startRun(commit: string | undefined) {
+    let run = Date.now();
+    while (this.runs.has(run)) {
+      run++;
+    }
+
+    this.runs.set(run, commit);
+    trimToSize(this.runs, MAX_RUNS);
+    this.markDirty();
+    return run;
+  }
+
+  /**
+   * Gets the commit the repo was at in the run, if it's known.
+   */
+  public getCommit(run: number) {
+    return this.runs.get(run);
+  }
+
+  /**
+   * Adds a result of the test.
+   */
+  public recordResult(testId: string, entry: ITestHistoryEntry) {
+    const entries = this.tests.get(testId) ?? [];
+    entries.push(entry);
+    if (entries.length > MAX_RESULTS_PER_TEST) {
+      entries.splice(0, entries.length - MAX_RESULTS_PER_TEST);
+    }
+
+    // re-insert so the map stays ordered by when tests last ran
+    this.tests.delete(testId);
+    this.tests.set(testId, entries);
+    trimToSize(this.tests, MAX_TESTS);
+    this.markDirty();
+  }
+
+  /**
+   * Records how long the file took to run.
+   */
+  public recordFileDuration(uri: string, duration: number) {
+    this.files.delete(uri);
+    this.files.set(uri, duration);
+    trimToSize(this.files, MAX_FILES);
+    this.markDirty();
+  }
+
+  /**
+   * Saves any results that haven't been saved yet, resolving once they're
+   * written.
+   */
+  public flush() {
+    this.scheduleSave.clear();
+    return this.dirty ? this.save() : this.saving;
+  }
+
+  /**
+   * @override
+   */
+  public dispose() {
+    this.flush();
+  }
+
+  private markDirty() {
+    this.dirty = true;
+    this.scheduleSave();
+  }
+
+  private save() {
+    this.dirty = false;
+    // wait for an earlier save, so an older one can't be written last
+    this.saving = this.saving.then(() => this.write());
+    return this.saving;
+  }
+
+  private async write() {
+    const data: ISerializedHistory = {
+      version: HISTORY_VERSION,
+      runs: [...this.runs],
+      tests: [...this.tests],
+      files: [...this.files],
+    };
+
+    try {
+      await vscode.workspace.fs.createDirectory(this.storageUri);
+      await vscode.workspace.fs.writeFile(
+        this.fileUri,
+        new TextEncoder().encode(JSON.stringify(data))
+      );
+    } catch (e) {
+      console.warn(`Error saving test history: ${e.stack || e.message}`);
+    }
+  }
+}
+
+const trimToSize = <K, V>(map: Map<K, V>, size: number) => {
+  for (const key of map.keys()) {
+    if (map.size <= size) {
+      break;
+    }
+    map.delete(key);
+  }
+};
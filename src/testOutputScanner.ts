@@ -8,12 +8,14 @@ import { decode as base64Decode } from 'js-base64';
 import { SourceMapConsumer } from 'source-map';
 import * as split from 'split2';
 import * as vscode from 'vscode';
+import { ITestFailure } from './testReport';
 import { getContentFromFilesystem } from './testTree';
 
 export const enum MochaEvent {
   Start = 'start',
   Pass = 'pass',
   Fail = 'fail',
+  Pending = 'pending',
   End = 'end',
 }
 
@@ -37,6 +39,12 @@ export interface IFailEvent extends IPassEvent {
   actual?: string;
 }
 
+export interface IPendingEvent {
+  title: string;
+  fullTitle: string;
+  file: string;
+}
+
 export interface IEndEvent {
   suites: number;
   tests: number;
@@ -51,6 +59,7 @@ export type MochaEventTuple =
   | [MochaEvent.Start, IStartEvent]
   | [MochaEvent.Pass, IPassEvent]
   | [MochaEvent.Fail, IFailEvent]
+  | [MochaEvent.Pending, IPendingEvent]
   | [MochaEvent.End, IEndEvent];
 
 export class TestOutputScanner implements vscode.Disposable {
@@ -69,15 +78,25 @@ export class TestOutputScanner implements vscode.Disposable {
   public readonly onOtherOutput = this.outputEventEmitter.event;
 
   /**
-   * Fired when the process encounters an error, or exits.
+   * Fired when the process encounters an error, or exits and all of its
+   * output has been read.
    */
   public readonly onRunnerError = this.onErrorEmitter.event;
 
   constructor(private readonly process: ChildProcessWithoutNullStreams, private args?: string[]) {
-    process.stdout.pipe(split()).on('data', this.processData);
-    process.stderr.pipe(split()).on('data', this.processData);
+    this.readFrom(process.stdout);
+    this.readFrom(process.stderr);
     process.on('error', e => this.onErrorEmitter.fire(e.message));
-    process.on('exit', code => this.onErrorEmitter.fire(`Test process exited with code ${code}`));
+    // 'exit' can come before the stdio is read, 'close' waits for it
+    process.on('close', code => this.onErrorEmitter.fire(`Test process exited with code ${code}`));
+  }
+
+  /**
+   * Reads mocha events and output from the stream, in addition to the
+   * process' stdio. Used by runners that report results on another channel.
+   */
+  public readFrom(stream: NodeJS.ReadableStream) {
+    stream.pipe(split()).on('data', this.processData);
   }
 
   /**
@@ -110,33 +129,105 @@ export class TestOutputScanner implements vscode.Disposable {
   };
 }
 
+/**
+ * Number of trailing output lines attached to tests that didn't run because
+ * the test process went away.
+ */
+const OUTPUT_TAIL_LINES = 30;
+
+const enum RunEndReason {
+  /** Mocha reported the end of the run */
+  Completed,
+  /** The test process exited or errored before mocha finished */
+  Exited,
+  /** The user cancelled the run */
+  Cancelled,
+}
+
+/**
+ * Finds or creates an item for a test result which wasn't in the map of
+ * tests that were expected to run.
+ */
+export type TestResolver = (fullTitle: string, file: string) => vscode.TestItem | undefined;
+
+/**
+ * Result of a test, reported as its final state is set in the run.
+ */
+export interface ITestResult {
+  test: vscode.TestItem;
+  state: vscode.TestResultState;
+  duration?: number;
+  /** Whether the test passed only after mocha retried it */
+  flaky?: boolean;
+  /** Details of why the test failed, if it did */
+  failure?: ITestFailure;
+}
+
+export interface IScanOptions {
+  /** Finds or creates items for results that weren't expected */
+  resolveTest?: TestResolver;
+  /** Called with the result of each test */
+  onResult?: (result: ITestResult) => void;
+  /**
+   * Called when a test fails. If it returns true, the failure isn't reported
+   * to the run, so the caller can retry the test and report it later.
+   */
+  deferFailure?: (test: vscode.TestItem, message: vscode.TestMessage) => boolean;
+  /**
+   * Whether to wait for the process to exit once mocha finishes, rather than
+   * stopping it, so it can write reports such as coverage.
+   */
+  waitForExit?: boolean;
+}
+
 export async function scanTestOutput(
   tests: Map<string, vscode.TestItem>,
   task: vscode.TestRun,
   scanner: TestOutputScanner,
-  cancellation: vscode.CancellationToken
+  cancellation: vscode.CancellationToken,
+  { resolveTest, onResult, deferFailure, waitForExit }: IScanOptions = {}
 ): Promise<void> {
+  const exited = new Promise<void>(resolve => scanner.onRunnerError(() => resolve()));
   const locationDerivations: Promise<void>[] = [];
+  const outputTail: string[] = [];
   let lastTest: vscode.TestItem | undefined;
+  let endEvent: IEndEvent | undefined;
+
+  const setResult = (
+    test: vscode.TestItem,
+    state: vscode.TestResultState,
+    duration?: number,
+    flaky?: boolean,
+    failure?: ITestFailure
+  ) => {
+    task.setState(test, state, duration);
+    onResult?.({ test, state, duration, flaky, failure });
+  };
+
+  const appendOutputLine = (str: string) => {
+    task.appendOutput(str + '\r\n');
+    outputTail.push(str);
+    if (outputTail.length > OUTPUT_TAIL_LINES) {
+      outputTail.shift();
+    }
+  };
 
   try {
     if (cancellation.isCancellationRequested) {
       return;
     }
 
-    await new Promise<void>(resolve => {
+    const reason = await new Promise<RunEndReason>(resolve => {
       cancellation.onCancellationRequested(() => {
-        resolve();
+        resolve(RunEndReason.Cancelled);
       });
 
       scanner.onRunnerError(err => {
-        task.appendOutput(err + '\r\n');
-        resolve();
+        appendOutputLine(err);
+        resolve(RunEndReason.Exited);
       });
 
-      scanner.onOtherOutput(str => {
-        task.appendOutput(str + '\r\n');
-      });
+      scanner.onOtherOutput(appendOutputLine);
 
       scanner.onMochaEvent(evt => {
         switch (evt[0]) {
@@ -144,23 +235,31 @@ export async function scanTestOutput(
             break; // no-op
           case MochaEvent.Pass:
             {
-              const title = evt[1].fullTitle;
-              const tcase = tests.get(title);
+              const { fullTitle: title, file, duration, currentRetry } = evt[1];
+              const tcase = tests.get(title) ?? resolveTest?.(title, file);
               task.appendOutput(` ${styles.green.open}√${styles.green.close} ${title}\r\n`);
               if (tcase) {
                 lastTest = tcase;
-                task.setState(tcase, vscode.TestResultState.Passed, evt[1].duration);
+                if (currentRetry > 0) {
+                  task.appendMessage(
+                    tcase,
+                    new vscode.TestMessage(`Flaky: passed after ${currentRetry} mocha retries`)
+                  );
+                }
+                setResult(tcase, vscode.TestResultState.Passed, duration, currentRetry > 0);
                 tests.delete(title);
               }
             }
             break;
           case MochaEvent.Fail:
             {
-              const { err, stack, duration, expected, actual, fullTitle: id } = evt[1];
+              const { err, stack, duration, expected, actual, file, fullTitle: id } = evt[1];
               let tcase = tests.get(id);
               // report failures on hook to the last-seen test, or first test if none run yet
               if (!tcase && id.includes('hook for')) {
                 tcase = lastTest ?? tests.values().next().value;
+              } else if (!tcase) {
+                tcase = resolveTest?.(id, file);
               }
 
               task.appendOutput(`${styles.red.open} x ${id}${styles.red.close}\r\n`);
@@ -190,27 +289,116 @@ export async function scanTestOutput(
                   message.location = location ?? testFirstLine;
                   message.actualOutput = String(actual);
                   message.expectedOutput = String(expected);
+                  if (deferFailure?.(tcase!, message)) {
+                    return;
+                  }
+
                   task.appendMessage(tcase!, message);
-                  task.setState(tcase!, vscode.TestResultState.Failed, duration);
+                  setResult(tcase!, vscode.TestResultState.Failed, duration, false, {
+                    message: err,
+                    stack,
+                    expected,
+                    actual,
+                  });
                 })
               );
             }
             break;
+          case MochaEvent.Pending:
+            {
+              const title = evt[1].fullTitle;
+              const tcase = tests.get(title) ?? resolveTest?.(title, evt[1].file);
+              task.appendOutput(` ${styles.cyan.open}-${styles.cyan.close} ${title}\r\n`);
+              if (tcase) {
+                lastTest = tcase;
+                setResult(tcase, vscode.TestResultState.Skipped);
+                tests.delete(title);
+              }
+            }
+            break;
           case MochaEvent.End:
-            resolve();
+            endEvent = evt[1];
+            resolve(RunEndReason.Completed);
             break;
         }
       });
     });
     await Promise.all(locationDerivations);
+    reconcileRemainingTests(
+      tests,
+      task,
+      setResult,
+      cancellation.isCancellationRequested ? RunEndReason.Cancelled : reason,
+      endEvent,
+      outputTail
+    );
+
+    if (waitForExit && reason === RunEndReason.Completed) {
+      await Promise.race([
+        exited,
+        new Promise<void>(resolve => cancellation.onCancellationRequested(() => resolve())),
+      ]);
+    }
   } catch (e) {
     task.appendOutput(e.stack || e.message);
   } finally {
     scanner.dispose();
-    task.end();
   }
 }
 
+/**
+ * Updates the state of tests which were queued but never reported a result
+ * by the time the run ended, so they don't stay "Queued" forever.
+ */
+function reconcileRemainingTests(
+  tests: Map<string, vscode.TestItem>,
+  task: vscode.TestRun,
+  setResult: (
+    test: vscode.TestItem,
+    state: vscode.TestResultState,
+    duration?: number,
+    flaky?: boolean,
+    failure?: ITestFailure
+  ) => void,
+  reason: RunEndReason,
+  endEvent?: IEndEvent,
+  outputTail: ReadonlyArray<string> = []
+) {
+  if (!tests.size) {
+    return;
+  }
+
+  switch (reason) {
+    case RunEndReason.Completed:
+      // Mocha finished normally, so anything we didn't hear about was either
+      // pending (skipped) or not matched by the run at all.
+      if (endEvent && tests.size > endEvent.pending) {
+        task.appendOutput(
+          `${tests.size - endEvent.pending} queued test(s) were not found in the test run\r\n`
+        );
+      }
+      for (const test of tests.values()) {
+        setResult(test, vscode.TestResultState.Skipped);
+      }
+      break;
+    case RunEndReason.Exited: {
+      const output = outputTail.join('\n');
+      const message = `Test process ended before this test ran. Last output:\n\n${output}`;
+      for (const test of tests.values()) {
+        task.appendMessage(test, new vscode.TestMessage(message));
+        setResult(test, vscode.TestResultState.Errored, undefined, false, { message });
+      }
+      break;
+    }
+    case RunEndReason.Cancelled:
+      // tests that didn't run are left without a result, which ending the
+      // run clears from the queue
+      break;
+  }
+
+  tests.clear();
+}
+
 const forceCRLF = (str: string) => str.replace(/(?<!\r)\n/gm, '\r\n');
 
 const tryMakeMarkdown = (message: string) => {
@@ -227,6 +415,21 @@ const tryMakeMarkdown = (message: string) => {
 
 const inlineSourcemapRe = /^\/\/# sourceMappingURL=data:application\/json;base64,(.+)/m;
 
+/**
+ * Reads the source map inlined in the compiled file, if it has one. The
+ * caller should destroy the consumer once it's done with it.
+ */
+export async function readInlineSourceMap(fileUri: string) {
+  const contents = await getContentFromFilesystem(vscode.Uri.parse(fileUri));
+  const sourcemapMatch = inlineSourcemapRe.exec(contents);
+  if (!sourcemapMatch) {
+    return undefined;
+  }
+
+  const decoded = base64Decode(sourcemapMatch[1]);
+  return new SourceMapConsumer(decoded, fileUri);
+}
+
 async function tryDeriveLocation(stack: string) {
   const parts = /(file:\/{3}.+):([0-9]+):([0-9]+)/.exec(stack);
   if (!parts) {
@@ -234,32 +437,32 @@ async function tryDeriveLocation(stack: string) {
   }
 
   const [, fileUri, line, col] = parts;
-  let sourceMap: SourceMapConsumer;
+  let sourceMap: SourceMapConsumer | undefined;
   try {
-    const contents = await getContentFromFilesystem(vscode.Uri.parse(fileUri));
-    const sourcemapMatch = inlineSourcemapRe.exec(contents);
-    if (!sourcemapMatch) {
+    sourceMap = await readInlineSourceMap(fileUri);
+    if (!sourceMap) {
       return;
     }
-
-    const decoded = base64Decode(sourcemapMatch[1]);
-    sourceMap = await new SourceMapConsumer(decoded, fileUri);
   } catch (e) {
     console.warn(`Error parsing sourcemap for ${fileUri}: ${e.stack}`);
     return;
   }
 
-  const position = sourceMap.originalPositionFor({
-    column: Number(col) - 1,
-    line: Number(line),
-  });
+  try {
+    const position = sourceMap.originalPositionFor({
+      column: Number(col) - 1,
+      line: Number(line),
+    });
 
-  if (position.line === null || position.column === null || position.source === null) {
-    return;
-  }
+    if (position.line === null || position.column === null || position.source === null) {
+      return;
+    }
 
-  return new vscode.Location(
-    vscode.Uri.parse(position.source),
-    new vscode.Position(position.line - 1, position.column)
-  );
+    return new vscode.Location(
+      vscode.Uri.parse(position.source),
+      new vscode.Position(position.line - 1, position.column)
+    );
+  } finally {
+    sourceMap.destroy();
+  }
 }
@@ -0,0 +1,211 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import { promises as fs } from 'fs';
+import * as path from 'path';
+import * as vscode from 'vscode';
+import { itemData, TestConstruct } from './testTree';
+
+/** Names of the files reports are written to, in the report directory */
+const JUNIT_FILE = 'test-results.xml';
+const JSON_FILE = 'test-results.json';
+
+/**
+ * Details of a test failure, from mocha.
+ */
+export interface ITestFailure {
+  message: string;
+  stack?: string | null;
+  expected?: string;
+  actual?: string;
+}
+
+interface IReportEntry {
+  id: string;
+  name: string;
+  /** Path of the test file, relative to the repo */
+  file: string;
+  state: vscode.TestResultState;
+  /** Duration in milliseconds */
+  duration?: number;
+  flaky: boolean;
+  failure?: ITestFailure;
+}
+
+const { Passed, Failed, Skipped, Errored } = vscode.TestResultState;
+
+/** Names of result states in the JSON summary */
+const stateNames: { [state: number]: string } = {
+  [Passed]: 'passed',
+  [Failed]: 'failed',
+  [Skipped]: 'skipped',
+  [Errored]: 'errored',
+};
+
+/** Escape sequences that color and style terminal output */
+const ansiRe = /\x1b\[[0-9;]*[A-Za-z]/g;
+
+/**
+ * Escapes text for XML, dropping the styling of colored output and other
+ * characters XML can't contain.
+ */
+const escapeXml = (text: string) =>
+  text
+    .replace(ansiRe, '')
+    .replace(/[\x00-\x08\x0B\x0C\x0E-\x1F]/g, '')
+    .replace(/&/g, '&amp;')
+    .replace(/</g, '&lt;')
+    .replace(/>/g, '&gt;')
+    .replace(/"/g, '&quot;');
+
+const toSeconds = (ms = 0) => (ms / 1000).toFixed(3);
+
+/**
+ * Collects the results of a test run, and writes them as JUnit XML and a
+ * JSON summary so they can be compared with CI results.
+ */
+export class TestReport {
+  private readonly entries = new Map<vscode.TestItem, IReportEntry>();
+  private readonly start = new Date();
+
+  constructor(private readonly folder: vscode.WorkspaceFolder) {}
+
+  /**
+   * Adds the result of a test. When a test runs again, such as on a retry,
+   * its later result replaces the earlier one.
+   */
+  public addResult(
+    test: vscode.TestItem,
+    state: vscode.TestResultState,
+    duration?: number,
+    flaky = false,
+    failure?: ITestFailure
+  ) {
+    const data = itemData.get(test);
+    this.entries.set(test, {
+      id: test.id,
+      name: data instanceof TestConstruct ? data.fullName : test.label,
+      file: test.uri ? vscode.workspace.asRelativePath(test.uri, false) : '',
+      state,
+      duration,
+      flaky,
+      failure,
+    });
+  }
+
+  /**
+   * Writes the reports to the directory, which is relative to the workspace
+   * folder, returning the paths written to.
+   */
+  public async write(directory: string) {
+    const dir = path.resolve(this.folder.uri.fsPath, directory);
+    const junitFile = path.join(dir, JUNIT_FILE);
+    const jsonFile = path.join(dir, JSON_FILE);
+    const end = new Date();
+    await fs.mkdir(dir, { recursive: true });
+    await Promise.all([
+      fs.writeFile(junitFile, this.toJUnit(end)),
+      fs.writeFile(jsonFile, JSON.stringify(this.toSummary(end), null, 2)),
+    ]);
+
+    return [junitFile, jsonFile];
+  }
+
+  private count(entries: ReadonlyArray<IReportEntry>) {
+    const withState = (state: vscode.TestResultState) =>
+      entries.filter(e => e.state === state).length;
+    return {
+      tests: entries.length,
+      passed: withState(Passed),
+      failed: withState(Failed),
+      errored: withState(Errored),
+      skipped: withState(Skipped),
+      flaky: entries.filter(e => e.flaky).length,
+      duration: entries.reduce((sum, e) => sum + (e.duration ?? 0), 0),
+    };
+  }
+
+  private toSummary(end: Date) {
+    const entries = [...this.entries.values()];
+    return {
+      start: this.start.toISOString(),
+      end: end.toISOString(),
+      totals: this.count(entries),
+      tests: entries.map(e => ({ ...e, state: stateNames[e.state] ?? 'unknown' })),
+    };
+  }
+
+  private toJUnit(end: Date) {
+    const byFile = new Map<string, IReportEntry[]>();
+    for (const entry of this.entries.values()) {
+      byFile.set(entry.file, [...(byFile.get(entry.file) ?? []), entry]);
+    }
+
+    const attrs = (values: { [name: string]: string | number }) =>
+      Object.entries(values)
+        .map(([name, value]) => `${name}="${escapeXml(String(value))}"`)
+        .join(' ');
+
+    const testcase = (entry: IReportEntry) => {
+      const head = `    <testcase ${attrs({
+        classname: entry.file,
+        name: entry.name,
+        time: toSeconds(entry.duration),
+      })}`;
+      const { failure } = entry;
+      switch (entry.state) {
+        case Failed:
+        case Errored: {
+          const tag = entry.state === Failed ? 'failure' : 'error';
+          const details = [
+            failure?.stack || failure?.message,
+            failure?.expected !== undefined && `Expected: ${failure.expected}`,
+            failure?.actual !== undefined && `Actual: ${failure.actual}`,
+          ].filter(Boolean);
+          return (
+            `${head}>\n      <${tag} ${attrs({ message: failure?.message ?? tag })}>` +
+            `${escapeXml(details.join('\n\n'))}</${tag}>\n    </testcase>`
+          );
+        }
+        case Skipped:
+          return `${head}>\n      <skipped/>\n    </testcase>`;
+        default:
+          return `${head}/>`;
+      }
+    };
+
+    const suites = [...byFile].map(([file, entries]) => {
+      const totals = this.count(entries);
+      return [
+        `  <testsuite ${attrs({
+          name: file,
+          tests: totals.tests,
+          failures: totals.failed,
+          errors: totals.errored,
+          skipped: totals.skipped,
+          time: toSeconds(totals.duration),
+          timestamp: this.start.toISOString(),
+        })}>`,
+        ...entries.map(testcase),
+        '  </testsuite>',
+      ].join('\n');
+    });
+
+    const totals = this.count([...this.entries.values()]);
+    return [
+      '<?xml version="1.0" encoding="UTF-8"?>',
+      `<testsuites ${attrs({
+        name: 'VS Code Tests',
+        tests: totals.tests,
+        failures: totals.failed,
+        errors: totals.errored,
+        skipped: totals.skipped,
+        time: toSeconds(end.getTime() - this.start.getTime()),
+      })}>`,
+      ...suites,
+      '</testsuites>',
+      '',
+    ].join('\n');
+  }
+}
@@ -0,0 +1,78 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import * as vscode from 'vscode';
+import { getContainedTestFiles, itemData, TestFolder } from './testTree';
+
+/**
+ * Duration assumed for files that haven't been run before, if there's no
+ * other data to estimate from.
+ */
+const DEFAULT_FILE_DURATION = 1000;
+
+/**
+ * Splits the tests into at most `count` shards by the file they're in. Shards
+ * are balanced by how long each file took to run previously.
+ */
+export const shardByFile = (
+  tests: ReadonlyArray<vscode.TestItem>,
+  count: number,
+  durations: ReadonlyMap<string, number>
+): vscode.TestItem[][] => {
+  const byFile = new Map<string, vscode.TestItem[]>();
+  for (const test of tests) {
+    const items = itemData.get(test) instanceof TestFolder ? getContainedTestFiles(test) : [test];
+    for (const item of items) {
+      const key = item.uri!.toString();
+      byFile.set(key, [...(byFile.get(key) ?? []), item]);
+    }
+  }
+
+  if (count < 2 || byFile.size < 2) {
+    return [tests.slice()];
+  }
+
+  const known = [...byFile.keys()]
+    .map(f => durations.get(f))
+    .filter((d): d is number => d !== undefined);
+  const fallback = known.length
+    ? known.reduce((a, b) => a + b, 0) / known.length
+    : DEFAULT_FILE_DURATION;
+
+  // Longest-first greedy assignment to the shard that's shortest so far
+  const files = [...byFile].map(([key, items]) => ({
+    items,
+    duration: durations.get(key) ?? fallback,
+  }));
+  files.sort((a, b) => b.duration - a.duration);
+
+  const shards = Array.from({ length: Math.min(count, files.length) }, () => ({
+    items: [] as vscode.TestItem[],
+    duration: 0,
+  }));
+  for (const file of files) {
+    const shortest = shards.reduce((a, b) => (b.duration < a.duration ? b : a));
+    shortest.items.push(...file.items);
+    shortest.duration += file.duration;
+  }
+
+  return shards.map(s => s.items);
+};
+
+/**
+ * Runs the tasks, with at most `limit` of them running at once.
+ */
+export const runWithConcurrency = async (
+  tasks: ReadonlyArray<() => Promise<void>>,
+  limit: number
+) => {
+  let next = 0;
+  const worker = async () => {
+    while (next < tasks.length) {
+      await tasks[next++]();
+    }
+  };
+
+  await Promise.all(Array.from({ length: Math.max(1, Math.min(limit, tasks.length)) }, worker));
+};
@@ -2,11 +2,17 @@
  * Copyright (C) Microsoft Corporation. All rights reserved.
  *--------------------------------------------------------*/
 
-import { join, relative } from 'path';
+import { basename, dirname, join, relative, sep } from 'path';
 import * as ts from 'typescript';
 import { TextDecoder } from 'util';
 import * as vscode from 'vscode';
-import { extractTestFromNode } from './sourceUtils';
+import {
+  declareVariables,
+  enterScope,
+  extractTestFromNode,
+  getLoopIterations,
+  Scope,
+} from './sourceUtils';
 
 const textDecoder = new TextDecoder('utf-8');
 
@@ -14,6 +20,45 @@ type ContentGetter = (uri: vscode.Uri) => Promise<string>;
 
 export const itemData = new WeakMap<vscode.TestItem, VSCodeTest>();
 
+/**
+ * Diagnostics for focused (`.only`) tests, which shouldn't be committed.
+ */
+export const focusedTestDiagnostics = vscode.languages.createDiagnosticCollection(
+  'selfhost-test-provider'
+);
+
+let getAnnotation: (item: vscode.TestItem) => string | undefined = () => undefined;
+
+/**
+ * Sets a provider for extra information shown in test descriptions, such as
+ * how often the test has been flaky, or how much of its code is covered.
+ */
+export const setAnnotationProvider = (provider: (item: vscode.TestItem) => string | undefined) => {
+  getAnnotation = provider;
+};
+
+/**
+ * Sets the description of the test from its data and annotations.
+ */
+export const updateDescription = (item: vscode.TestItem) => {
+  const data = itemData.get(item);
+  const parts: string[] = [];
+  if (data instanceof TestDynamic) {
+    parts.push(data.skipped ? 'skipped' : 'names known at runtime');
+  } else if (data instanceof TestConstruct && data.skipped) {
+    parts.push('skipped');
+  } else if (data instanceof TestCase && data.foundAtRuntime) {
+    parts.push('found at runtime');
+  }
+
+  const annotation = getAnnotation(item);
+  if (annotation) {
+    parts.push(annotation);
+  }
+
+  item.description = parts.length ? parts.join(', ') : undefined;
+};
+
 /**
  * Tries to guess which workspace folder VS Code is in.
  */
@@ -38,6 +83,20 @@ export const guessWorkspaceFolder = async () => {
   return undefined;
 };
 
+/**
+ * Maps a compiled file in an `out` folder back to its TypeScript source.
+ */
+export const getSourceUriForCompiledFile = (file: string) =>
+  vscode.Uri.file(
+    file.replace(/([\\/])out([\\/])(?!.*[\\/]out[\\/])/, '$1src$2').replace(/\.js$/, '.ts')
+  );
+
+/**
+ * Maps a TypeScript source file to the file it's compiled to in `out`.
+ */
+export const getCompiledPathForSourceFile = (file: string) =>
+  file.replace(/([\\/])src([\\/])(?!.*[\\/]src[\\/])/, '$1out$2').replace(/\.ts$/, '.js');
+
 export const getContentFromFilesystem: ContentGetter = async uri => {
   try {
     const rawContent = await vscode.workspace.fs.readFile(uri);
@@ -48,6 +107,69 @@ export const getContentFromFilesystem: ContentGetter = async uri => {
   }
 };
 
+/**
+ * Layer folders in the VS Code source, which determine the environments a
+ * test file can run in.
+ */
+export const layers: ReadonlyArray<string> = [
+  'common',
+  'browser',
+  'node',
+  'electron-sandbox',
+  'electron-browser',
+  'electron-main',
+  'worker',
+];
+
+/**
+ * Pseudo-layer for tests of built-in extensions, which run in the extension host.
+ */
+export const EXTENSION_HOST_LAYER = 'extension-host';
+
+const extensionTestRe = /\/extensions\/([^/]+)\/src\//;
+
+/**
+ * Gets the name of the built-in extension the file is in, if any.
+ */
+export const getExtensionName = (uri: vscode.Uri) => extensionTestRe.exec(uri.path)?.[1];
+
+/**
+ * Gets the layer a file is in from the nearest layer folder in its path.
+ */
+export const getLayer = (uri: vscode.Uri) => {
+  if (getExtensionName(uri)) {
+    return EXTENSION_HOST_LAYER;
+  }
+
+  const segments = uri.path.split('/');
+  for (let i = segments.length - 2; i >= 0; i--) {
+    if (layers.includes(segments[i])) {
+      return segments[i];
+    }
+  }
+
+  return undefined;
+};
+
+/**
+ * Gets the test files in the item and any folders below it.
+ */
+export const getContainedTestFiles = (item: vscode.TestItem): vscode.TestItem[] => {
+  const data = itemData.get(item);
+  if (data instanceof TestFile) {
+    return [item];
+  }
+
+  return data instanceof TestFolder ? item.children.all.flatMap(getContainedTestFiles) : [];
+};
+
+export const enum TreeGrouping {
+  /** Files are grouped by the folders they're in */
+  Folder = 'folder',
+  /** Files are grouped by layer, and then by folder */
+  Layer = 'layer',
+}
+
 export class TestFile {
   public hasBeenRead = false;
 
@@ -61,7 +183,35 @@ export class TestFile {
   }
 
   public getLabel() {
-    return relative(join(this.workspaceFolder.uri.fsPath, 'src', 'vs'), this.uri.fsPath);
+    return basename(this.uri.fsPath);
+  }
+
+  public get layer() {
+    return getLayer(this.uri);
+  }
+
+  /**
+   * Gets the names of the folders the file is shown under in the tree.
+   * `test` folders are left out since nearly every test is in one.
+   */
+  public getFolderPath(grouping: TreeGrouping) {
+    // Tests in `src/vs` are shown relative to it, since nearly all of them
+    // are there. Others, like extension tests, are relative to the root.
+    const srcVs = join(this.workspaceFolder.uri.fsPath, 'src', 'vs');
+    const inSrcVs = this.uri.fsPath.startsWith(srcVs + sep);
+    const segments = relative(
+      inSrcVs ? srcVs : this.workspaceFolder.uri.fsPath,
+      dirname(this.uri.fsPath)
+    )
+      .split(sep)
+      .filter(s => s && s !== 'test' && (inSrcVs || s !== 'src'));
+
+    if (grouping === TreeGrouping.Folder) {
+      return segments;
+    }
+
+    const layer = this.layer;
+    return layer ? [layer, ...segments.filter(s => s !== layer)] : ['other', ...segments];
   }
 
   public async updateFromDisk(item: vscode.TestItem) {
@@ -90,51 +240,190 @@ export class TestFile {
       const parents: { item: vscode.TestItem; children: vscode.TestItem[] }[] = [
         { item: file, children: [] },
       ];
-      const traverse = (node: ts.Node) => {
+      const diagnostics: vscode.Diagnostic[] = [];
+      const traverse = (node: ts.Node, scope: Scope) => {
         const parent = parents[parents.length - 1];
-        const childData = extractTestFromNode(ast, node, itemData.get(parent.item)!);
+        declareVariables(node, scope);
+
+        const loop = getLoopIterations(node, scope);
+        if (loop) {
+          for (const iterationScope of loop.scopes) {
+            traverse(loop.body, iterationScope);
+          }
+          return;
+        }
+
+        const childData = extractTestFromNode(ast, node, itemData.get(parent.item)!, scope);
         if (!childData) {
-          ts.forEachChild(node, traverse);
+          const inner = enterScope(node, scope);
+          ts.forEachChild(node, child => traverse(child, inner));
+          return;
+        }
+
+        const id =
+          childData instanceof TestDynamic
+            ? `${file.uri}/${childData.fullName}#dynamic`.toLowerCase()
+            : `${file.uri}/${childData.fullName}`.toLowerCase();
+        // tests expanded from loops, or several dynamic tests in a suite, can
+        // produce the same item more than once.
+        if (parent.children.some(c => c.id === id)) {
           return;
         }
 
-        const id = `${file.uri}/${childData.fullName}`.toLowerCase();
         const item = vscode.test.createTestItem(id, childData.name, file.uri);
         itemData.set(item, childData);
         item.range = childData.range;
+        updateDescription(item);
+        if (childData.modifier === TestModifier.Only) {
+          diagnostics.push(createFocusedDiagnostic(ast, node as ts.CallExpression));
+        }
         parent.children.push(item);
 
         if (childData instanceof TestSuite) {
+          const inner = new Map(scope);
           parents.push({ item: item, children: [] });
-          ts.forEachChild(node, traverse);
+          ts.forEachChild(node, child => traverse(child, inner));
           item.children.all = parents.pop()!.children;
         }
       };
 
-      ts.forEachChild(ast, traverse);
+      const rootScope: Scope = new Map();
+      ts.forEachChild(ast, node => traverse(node, rootScope));
       file.error = undefined;
       file.children.all = parents[0].children;
+      focusedTestDiagnostics.set(this.uri, diagnostics);
       this.hasBeenRead = true;
     } catch (e) {
       file.error = String(e.stack || e.message);
     }
   }
+
+  /**
+   * Creates an item for a test reported at runtime that wasn't found in the
+   * static tree. It's placed under the dynamic node of the suite it was
+   * declared in if there is one, or otherwise under the deepest suite whose
+   * name prefixes its title.
+   */
+  public createRuntimeTest(file: vscode.TestItem, fullTitle: string) {
+    let dynamic: vscode.TestItem | undefined;
+    let suite: vscode.TestItem | undefined;
+    let parent: vscode.TestItem | undefined = file;
+    while (parent) {
+      const children: readonly vscode.TestItem[] = parent.children.all;
+      parent = undefined;
+      for (const child of children) {
+        const data = itemData.get(child);
+        if (
+          data instanceof TestDynamic &&
+          (!data.fullName || fullTitle.startsWith(`${data.fullName} `))
+        ) {
+          dynamic = child;
+        } else if (data instanceof TestSuite && fullTitle.startsWith(`${data.fullName} `)) {
+          parent = suite = child;
+        }
+      }
+    }
+
+    const container = dynamic ?? suite ?? file;
+    const id = `${file.uri}/${fullTitle}`.toLowerCase();
+    const existing = container.children.get(id);
+    if (existing) {
+      return existing;
+    }
+
+    const containerData = itemData.get(container);
+    const parentData =
+      containerData instanceof TestConstruct && containerData.fullName ? containerData : undefined;
+    const data = new TestCase(
+      parentData ? fullTitle.slice(parentData.fullName.length + 1) : fullTitle,
+      containerData instanceof TestConstruct ? containerData.range : new vscode.Range(0, 0, 0, 0),
+      parentData
+    );
+
+    data.foundAtRuntime = !dynamic;
+
+    const item = vscode.test.createTestItem(id, data.name, file.uri);
+    itemData.set(item, data);
+    item.range = data.range;
+    updateDescription(item);
+    container.children.add(item);
+    return item;
+  }
+}
+
+const createFocusedDiagnostic = (ast: ts.SourceFile, node: ts.CallExpression) => {
+  const start = ast.getLineAndCharacterOfPosition(node.expression.getStart(ast));
+  const end = ast.getLineAndCharacterOfPosition(node.expression.end);
+  const diagnostic = new vscode.Diagnostic(
+    new vscode.Range(start.line, start.character, end.line, end.character),
+    'Only focused tests will run in this file. Remove `.only` before committing.',
+    vscode.DiagnosticSeverity.Warning
+  );
+  diagnostic.source = 'VS Code Tests';
+  return diagnostic;
+};
+
+export const enum TestModifier {
+  Skip = 'skip',
+  Only = 'only',
+}
+
+export class TestFolder {
+  constructor(public readonly path: ReadonlyArray<string>) {}
+
+  public getId() {
+    return `folder:${this.path.join('/')}`;
+  }
+
+  public getLabel() {
+    return this.path[this.path.length - 1];
+  }
 }
 
 export abstract class TestConstruct {
   public fullName: string;
 
+  /**
+   * Whether the test is skipped, either directly or by a skipped parent suite.
+   */
+  public readonly skipped: boolean;
+
+  /**
+   * Whether the test is in a `flakySuite`, and so gets retried if it fails.
+   */
+  public readonly flaky: boolean;
+
   constructor(
     public readonly name: string,
     public readonly range: vscode.Range,
-    parent?: TestConstruct
+    parent?: TestConstruct,
+    public readonly modifier?: TestModifier,
+    flaky = false
   ) {
     this.fullName = parent ? `${parent.fullName} ${name}` : name;
+    this.skipped = modifier === TestModifier.Skip || !!parent?.skipped;
+    this.flaky = flaky || !!parent?.flaky;
   }
 }
 
 export class TestSuite extends TestConstruct {}
 
-export class TestCase extends TestConstruct {}
+export class TestCase extends TestConstruct {
+  /**
+   * Whether the test was reported at runtime without being found in the source.
+   */
+  public foundAtRuntime = false;
+}
+
+/**
+ * Placeholder for tests whose names can only be known at runtime. Items for
+ * them are created under it as their results are reported.
+ */
+export class TestDynamic extends TestConstruct {
+  constructor(range: vscode.Range, parent?: TestConstruct, modifier?: TestModifier) {
+    super('dynamic tests', range, parent, modifier);
+    this.fullName = parent?.fullName ?? '';
+  }
+}
 
-export type VSCodeTest = TestFile | TestSuite | TestCase;
+export type VSCodeTest = TestFolder | TestFile | TestSuite | TestCase | TestDynamic;
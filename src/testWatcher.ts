@@ -0,0 +1,120 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import * as vscode from 'vscode';
+import { debounce } from './debounce';
+import { getSourceUriForCompiledFile } from './testTree';
+
+/**
+ * Compiled output that's watched for changes. Tests rerun once the watch
+ * build has written here, rather than as soon as sources are saved.
+ */
+const COMPILED_OUTPUT_PATTERNS = ['out/**/*.js', 'extensions/*/out/**/*.js'];
+
+/**
+ * Delay after the last change to compiled output before tests rerun, so a
+ * build that writes many files only causes one run.
+ */
+const RERUN_DELAY = 1000;
+
+export const STOP_WATCHING_COMMAND = 'selfhost-test-provider.stopWatching';
+
+/** Sources for cancelling each active watch */
+const watches = new Set<vscode.CancellationTokenSource>();
+
+/**
+ * Stops all tests that are being watched.
+ */
+export const stopWatching = () => {
+  for (const watch of watches) {
+    watch.cancel();
+  }
+};
+
+export interface IWatchOptions {
+  /** Runs the tests, or the original selection if undefined, resolving once the run ends */
+  run: (
+    tests: ReadonlyArray<vscode.TestItem> | undefined,
+    token: vscode.CancellationToken
+  ) => Thenable<void>;
+  /** Gets which of the watched tests are affected by the changed source files */
+  getAffected: (
+    tests: ReadonlyArray<vscode.TestItem>,
+    sources: ReadonlyArray<vscode.Uri>
+  ) => Thenable<vscode.TestItem[]>;
+}
+
+/**
+ * Runs the tests, and then reruns those affected by later builds until the
+ * watch is cancelled or stopped.
+ */
+export async function watchTests(
+  folder: vscode.WorkspaceFolder,
+  tests: ReadonlyArray<vscode.TestItem>,
+  token: vscode.CancellationToken,
+  { run, getAffected }: IWatchOptions
+) {
+  const cts = new vscode.CancellationTokenSource();
+  const cancelListener = token.onCancellationRequested(() => cts.cancel());
+  if (token.isCancellationRequested) {
+    cts.cancel();
+  }
+  watches.add(cts);
+
+  const status = vscode.window.createStatusBarItem(vscode.StatusBarAlignment.Left);
+  status.text = `$(eye) Watching ${tests.length} test item(s)`;
+  status.tooltip = 'Tests rerun when their compiled output changes. Click to stop watching.';
+  status.command = STOP_WATCHING_COMMAND;
+  status.show();
+
+  const changed = new Map<string, vscode.Uri>();
+  let runs = Promise.resolve();
+  /** Queues the run after earlier ones, so a run that fails doesn't stop later ones */
+  const enqueue = (fn: () => Thenable<void>) => {
+    runs = runs.then(fn).then(undefined, e => {
+      console.warn(`Error running watched tests: ${e.stack || e.message}`);
+    });
+  };
+
+  const rerun = debounce(RERUN_DELAY, () => {
+    const sources = [...changed.values()];
+    changed.clear();
+    enqueue(async () => {
+      const affected = await getAffected(tests, sources);
+      if (affected.length && !cts.token.isCancellationRequested) {
+        await run(affected, cts.token);
+      }
+    });
+  });
+
+  const onCompiledChange = (uri: vscode.Uri) => {
+    const source = getSourceUriForCompiledFile(uri.fsPath);
+    changed.set(source.toString(), source);
+    rerun();
+  };
+
+  const watchers = COMPILED_OUTPUT_PATTERNS.map(glob => {
+    const watcher = vscode.workspace.createFileSystemWatcher(
+      new vscode.RelativePattern(folder, glob)
+    );
+    watcher.onDidCreate(onCompiledChange);
+    watcher.onDidChange(onCompiledChange);
+    return watcher;
+  });
+
+  try {
+    enqueue(() => run(undefined, cts.token));
+    if (!cts.token.isCancellationRequested) {
+      await new Promise<void>(resolve => cts.token.onCancellationRequested(() => resolve()));
+    }
+  } finally {
+    rerun.clear();
+    watchers.forEach(w => w.dispose());
+    status.dispose();
+    cancelListener.dispose();
+    watches.delete(cts);
+    await runs;
+    cts.dispose();
+  }
+}
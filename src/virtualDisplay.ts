@@ -0,0 +1,76 @@
+/*---------------------------------------------------------
+ * Copyright (C) Microsoft Corporation. All rights reserved.
+ *--------------------------------------------------------*/
+
+import { spawn } from 'child_process';
+
+/** How long to wait for Xvfb to report its display before giving up */
+const START_TIMEOUT = 10000;
+
+/**
+ * Electron flags used for tests on a virtual display, matching the repo's
+ * headless CI runs.
+ */
+export const HEADLESS_ELECTRON_ARGS: ReadonlyArray<string> = [
+  '--no-sandbox',
+  '--disable-dev-shm-usage',
+  '--use-gl=swiftshader',
+];
+
+export interface IVirtualDisplay {
+  /** Value for the `DISPLAY` environment variable */
+  display: string;
+  dispose(): void;
+}
+
+/**
+ * Gets whether processes started with the environment have no display to
+ * open windows on.
+ */
+export const needsVirtualDisplay = (env: NodeJS.ProcessEnv) =>
+  process.platform === 'linux' && !env.DISPLAY && !env.WAYLAND_DISPLAY;
+
+/**
+ * Starts an Xvfb server on a free display, resolving once it's ready.
+ */
+export const startVirtualDisplay = () =>
+  new Promise<IVirtualDisplay>((resolve, reject) => {
+    // -displayfd makes Xvfb pick a free display, and write its number once ready
+    const xvfb = spawn(
+      'Xvfb',
+      ['-displayfd', '1', '-screen', '0', '1280x1024x24', '-nolisten', 'tcp'],
+      { stdio: ['ignore', 'pipe', 'pipe'] }
+    );
+
+    let stderr = '';
+    const fail = (message: string) => {
+      clearTimeout(timeout);
+      xvfb.kill();
+      reject(
+        new Error(
+          `No display is available, and Xvfb could not be started: ${message}. ` +
+            'Install Xvfb, or set DISPLAY to an X server tests can use.'
+        )
+      );
+    };
+
+    const timeout = setTimeout(() => fail('timed out waiting for it to start'), START_TIMEOUT);
+    xvfb.on('error', err => fail(err.message));
+    xvfb.on('exit', code => fail(`exited with code ${code}. ${stderr.trim()}`));
+    xvfb.stderr.on('data', (chunk: Buffer) => {
+      stderr = (stderr + chunk.toString()).slice(-2000);
+    });
+
+    let stdout = '';
+    xvfb.stdout.on('data', (chunk: Buffer) => {
+      stdout += chunk.toString();
+      const match = /^(\d+)\n/.exec(stdout);
+      if (!match) {
+        return;
+      }
+
+      clearTimeout(timeout);
+      xvfb.removeAllListeners('exit');
+      resolve({ display: `:${match[1]}`, dispose: () => xvfb.kill() });
+    });
+  });
@@ -2,13 +2,30 @@
  * Copyright (C) Microsoft Corporation. All rights reserved.
  *--------------------------------------------------------*/
 
-import { spawn } from 'child_process';
+import { ChildProcess, spawn } from 'child_process';
+import { randomBytes } from 'crypto';
 import { promises as fs } from 'fs';
 import { AddressInfo, createServer } from 'net';
+import { tmpdir } from 'os';
 import * as path from 'path';
 import * as vscode from 'vscode';
+import { verifyElectronBinary } from './electronPreflight';
+import { DEFAULT_PROFILE_OPTIONS, IProfileOptions } from './profileOptions';
+import { getDefaultArgs, getDefaultEnv, getDefaultTimeout, getTestScript } from './settings';
 import { TestOutputScanner } from './testOutputScanner';
-import { itemData, TestCase, TestFile, TestSuite } from './testTree';
+import {
+  EXTENSION_HOST_LAYER,
+  getCompiledPathForSourceFile,
+  getContainedTestFiles,
+  getExtensionName,
+  itemData,
+  TestCase,
+  TestDynamic,
+  TestFile,
+  TestFolder,
+  TestSuite,
+} from './testTree';
+import { HEADLESS_ELECTRON_ARGS, needsVirtualDisplay, startVirtualDisplay } from './virtualDisplay';
 
 /**
  * From MDN
@@ -16,44 +33,355 @@ import { itemData, TestCase, TestFile, TestSuite } from './testTree';
  */
 const escapeRe = (s: string) => s.replace(/[.*+\-?^${}()|[\]\\]/g, '\\$&');
 
-const TEST_ELECTRON_SCRIPT_PATH = 'test/unit/electron/index.js';
-const TEST_BROWSER_SCRIPT_PATH = 'test/unit/browser/index.js';
+/** Layers whose tests load in the Electron renderer */
+const ELECTRON_LAYERS: ReadonlySet<string> = new Set([
+  'common',
+  'browser',
+  'node',
+  'electron-sandbox',
+  'electron-browser',
+]);
+
+/** Layers whose tests load in a plain browser */
+const BROWSER_LAYERS: ReadonlySet<string> = new Set(['common', 'browser']);
+
+/** Browser engine that can be debugged, over the Chrome DevTools Protocol */
+const DEBUGGABLE_BROWSER = 'chromium';
+
+/**
+ * Gets the browser engine the browser test script's arguments select. The
+ * script runs tests in every engine if none is given.
+ */
+const getBrowserType = (args: ReadonlyArray<string>) => {
+  for (let i = 0; i < args.length; i++) {
+    if (args[i] === '--browser') {
+      return args[i + 1];
+    } else if (args[i].startsWith('--browser=')) {
+      return args[i].slice('--browser='.length);
+    }
+  }
+
+  return undefined;
+};
+
+/** Layers whose tests load in plain Node.js */
+const NODE_LAYERS: ReadonlySet<string> = new Set(['common', 'node', 'electron-main']);
+
+/** Module in this extension's output that starts test scripts with a selection */
+const TEST_BOOTSTRAP_MODULE = 'testBootstrap.js';
+/** Module in this extension's output that's loaded as the extension tests */
+const EXTENSION_TESTS_MODULE = 'extensionHostTests.js';
+/** Environment variable with the path of the extension tests module's config */
+const EXTENSION_TESTS_CONFIG_VAR = 'VSCODE_SELFHOST_TEST_CONFIG';
+
+/**
+ * Configuration passed to the extension tests module.
+ */
+interface IExtensionTestConfig {
+  /** Port to report mocha events to */
+  port: number;
+  /** Path of the VS Code repo */
+  repo: string;
+  /** Pattern of test titles to run */
+  grep?: string;
+  /** Compiled test files to run */
+  files: string[];
+  /** Timeout of each test in milliseconds */
+  timeout?: number;
+}
+
+/**
+ * Configuration passed to the bootstrap module.
+ */
+interface IBootstrapConfig {
+  /** Arguments added to the test script's own */
+  args: string[];
+  /** Debugging of the browser the test script launches, if any */
+  debugBrowser?: IDebugBrowserConfig;
+}
+
+interface IDebugBrowserConfig {
+  /** Port the browser listens on for the debugger */
+  port: number;
+  /** Port of the server pages wait on before loading tests */
+  waitServerPort: number;
+}
 
 const ATTACH_CONFIG_NAME = 'Attach to VS Code';
+const BROWSER_ATTACH_CONFIG_NAME = 'Attach to VS Code Browser Tests';
+const NODE_ATTACH_CONFIG_NAME = 'Attach to VS Code Node.js Tests';
+const EXTENSION_HOST_ATTACH_CONFIG_NAME = 'Attach to VS Code Extension Host Tests';
 const DEBUG_TYPE = 'pwa-chrome';
 
+/**
+ * Writes the JSON to a new temporary file, which can be removed once the
+ * process that reads it exits, or fails to start.
+ */
+const writeTempJson = async (prefix: string, data: unknown) => {
+  const file = path.join(tmpdir(), `${prefix}-${randomBytes(8).toString('hex')}.json`);
+  await fs.writeFile(file, JSON.stringify(data));
+  const remove = () => fs.unlink(file).catch(() => undefined);
+  return {
+    file,
+    remove,
+    removeOnExit: (cp: ChildProcess) => {
+      cp.once('exit', remove);
+      // a process that fails to start errors without exiting
+      cp.once('error', remove);
+    },
+  };
+};
+
+/**
+ * Gets the configuration with the name from the folder's launch.json, if any.
+ */
+const getLaunchConfig = (folder: vscode.WorkspaceFolder, name: string) =>
+  vscode.workspace
+    .getConfiguration('launch', folder.uri)
+    .get<vscode.DebugConfiguration[]>('configurations', [])
+    .find(c => c.name === name);
+
+/**
+ * Gets a port that's free to listen on, so debug sessions of several test
+ * processes, or other programs, don't conflict.
+ */
+const getFreePort = () =>
+  new Promise<number>((resolve, reject) => {
+    const server = createServer();
+    server.once('error', reject);
+    server.listen(0, '127.0.0.1', () => {
+      const { port } = server.address() as AddressInfo;
+      server.close(() => resolve(port));
+    });
+  });
+
+const exists = async (file: string) => {
+  try {
+    await fs.stat(file);
+    return true;
+  } catch {
+    return false;
+  }
+};
+
+/**
+ * Server the test process waits on before running tests, so breakpoints can
+ * be set first.
+ */
+interface IWaitServer {
+  port: number;
+  ready(): void;
+  dispose(): void;
+}
+
+/**
+ * Gets whether the tests are selected by their files rather than by titles.
+ */
+const isSelectedByFile = (test: vscode.TestItem) => {
+  const data = itemData.get(test);
+  return (
+    data instanceof TestFile ||
+    data instanceof TestFolder ||
+    // dynamic tests at the root of a file can only be selected by the file
+    (data instanceof TestDynamic && !data.fullName)
+  );
+};
+
+/**
+ * Gets the titles of all known tests in and below the item.
+ */
+const getTestTitles = (item: vscode.TestItem): string[] => {
+  const data = itemData.get(item);
+  return data instanceof TestCase ? [data.fullName] : item.children.all.flatMap(getTestTitles);
+};
+
+/**
+ * Gets patterns that match the exact titles of the tests in the suite. If
+ * the suite has tests whose names are only known at runtime, everything in
+ * the suite is matched instead.
+ */
+const getSuitePatterns = (suite: vscode.TestItem, fullName: string): string[] => {
+  const hasDynamic = (item: vscode.TestItem): boolean =>
+    itemData.get(item) instanceof TestDynamic || item.children.all.some(hasDynamic);
+  const titles = getTestTitles(suite);
+  return hasDynamic(suite) || !titles.length
+    ? [escapeRe(fullName) + ' ']
+    : titles.map(title => escapeRe(title) + '$');
+};
+
+/**
+ * Gets the grep pattern and files which select the given tests.
+ */
+const getTestSelection = (filter: ReadonlyArray<vscode.TestItem>) => {
+  const grepRe: string[] = [];
+  const files = new Map<string, vscode.Uri>();
+  const addFile = (uri: vscode.Uri) => files.set(uri.toString(), uri);
+  for (const test of filter) {
+    const data = itemData.get(test);
+    if (data instanceof TestFolder) {
+      getContainedTestFiles(test).forEach(file => addFile(file.uri!));
+      continue;
+    }
+
+    // always scope to the file, so same-named tests in other files don't run
+    addFile(test.uri!);
+    if (isSelectedByFile(test)) {
+      continue;
+    } else if (data instanceof TestCase) {
+      grepRe.push(escapeRe(data.fullName) + '$');
+    } else if (data instanceof TestSuite) {
+      grepRe.push(...getSuitePatterns(test, data.fullName));
+    } else if (data instanceof TestDynamic) {
+      grepRe.push(escapeRe(data.fullName) + ' ');
+    }
+  }
+
+  return {
+    grep: grepRe.length ? `^(${grepRe.join('|')})` : undefined,
+    files: [...files.values()],
+  };
+};
+
+/**
+ * Splits the tests so that title patterns only apply in the files they were
+ * chosen in. Tests selected by file run together without a pattern, and
+ * files with chosen tests only share a process if their patterns don't
+ * match tests in each other.
+ */
+const splitBySelectionScope = (tests: ReadonlyArray<vscode.TestItem>) => {
+  const byFile: vscode.TestItem[] = [];
+  const byTitle = new Map<string, vscode.TestItem[]>();
+  for (const test of tests) {
+    if (isSelectedByFile(test)) {
+      byFile.push(test);
+    } else {
+      const key = test.uri!.toString();
+      byTitle.set(key, [...(byTitle.get(key) ?? []), test]);
+    }
+  }
+
+  const groups: { items: vscode.TestItem[]; patterns: RegExp[]; titles: string[] }[] = [];
+  for (const items of byTitle.values()) {
+    let file = items[0];
+    while (file.parent && !(itemData.get(file) instanceof TestFile)) {
+      file = file.parent;
+    }
+
+    const pattern = new RegExp(getTestSelection(items).grep!);
+    const titles = getTestTitles(file);
+    const group = groups.find(
+      g =>
+        !g.titles.some(t => pattern.test(t)) && !g.patterns.some(p => titles.some(t => p.test(t)))
+    );
+    if (group) {
+      group.items.push(...items);
+      group.patterns.push(pattern);
+      group.titles.push(...titles);
+    } else {
+      groups.push({ items, patterns: [pattern], titles });
+    }
+  }
+
+  return [...(byFile.length ? [byFile] : []), ...groups.map(g => g.items)];
+};
+
 export abstract class VSCodeTestRunner {
-  constructor(protected readonly repoLocation: vscode.WorkspaceFolder) {}
+  /**
+   * Whether the runner's test script can collect coverage.
+   */
+  public static readonly supportsCoverage: boolean = false;
+
+  /**
+   * Name of the environment the runner executes tests in.
+   */
+  public abstract readonly environment: string;
+
+  /**
+   * Layers whose tests can be loaded in the runner's environment.
+   */
+  protected abstract readonly layers: ReadonlySet<string>;
+
+  constructor(
+    protected readonly repoLocation: vscode.WorkspaceFolder,
+    protected readonly options: IProfileOptions = DEFAULT_PROFILE_OPTIONS
+  ) {}
+
+  /**
+   * Gets whether tests in the layer can be run by this runner. Tests outside
+   * of any known layer are assumed to be runnable.
+   */
+  public canRunLayer(layer: string | undefined) {
+    return !layer || this.layers.has(layer);
+  }
 
   public async run(baseArgs: ReadonlyArray<string>, filter?: ReadonlyArray<vscode.TestItem>) {
-    const args = this.prepareArguments(baseArgs, filter);
-    const cp = spawn(await this.binaryPath(), args, {
-      cwd: this.repoLocation.uri.fsPath,
-      stdio: 'pipe',
-      env: this.getEnvironment(),
+    await this.preflight();
+    const { args, selection } = this.prepareArguments(baseArgs, filter, false);
+    const bootstrap = await this.bootstrap(args, { args: selection });
+    const cp = await this.spawnTestProcess(bootstrap.args).catch(e => {
+      bootstrap.remove();
+      throw e;
     });
-
-    return new TestOutputScanner(cp, args);
+    bootstrap.removeOnExit(cp);
+    return new TestOutputScanner(cp, [...args, ...selection]);
   }
 
   public async debug(baseArgs: ReadonlyArray<string>, filter?: ReadonlyArray<vscode.TestItem>) {
+    await this.preflight();
     const server = this.createWaitServer();
-    const args = [
-      ...this.prepareArguments(baseArgs, filter),
-      '--remote-debugging-port=9222',
-      '--timeout=0',
-      `--waitServer=${server.port}`,
-    ];
+    const debugPort = await getFreePort();
+    const { args, selection } = this.prepareArguments(baseArgs, filter, true);
+    const bootstrap = await this.bootstrap(args, {
+      args: selection,
+      debugBrowser: this.getBrowserDebugConfig(server.port, debugPort),
+    });
+    const cp = await this.spawnTestProcess(
+      this.prepareDebugArguments(bootstrap.args, server.port, debugPort)
+    ).catch(e => {
+      bootstrap.remove();
+      server.dispose();
+      throw e;
+    });
+    bootstrap.removeOnExit(cp);
+    this.attachDebugger(cp, debugPort, server);
+    const shownArgs = this.prepareDebugArguments([...args, ...selection], server.port, debugPort);
+    return new TestOutputScanner(cp, shownArgs);
+  }
+
+  /**
+   * Splits the tests into groups which each need their own test process.
+   * A group is undefined if it should run everything the runner supports.
+   */
+  public groupByProcess(
+    tests: ReadonlyArray<vscode.TestItem>,
+    runAll: boolean
+  ): (ReadonlyArray<vscode.TestItem> | undefined)[] {
+    return runAll ? [undefined] : splitBySelectionScope(tests);
+  }
 
-    const cp = spawn(await this.binaryPath(), args, {
+  /**
+   * Checks the runner can start the test process, throwing if not.
+   */
+  protected async preflight(): Promise<void> {
+    // no-op by default
+  }
+
+  protected async spawnTestProcess(args: ReadonlyArray<string>, env = this.getEnvironment()) {
+    return spawn(await this.binaryPath(), args, {
       cwd: this.repoLocation.uri.fsPath,
       stdio: 'pipe',
-      env: this.getEnvironment(),
+      env,
     });
+  }
 
+  /**
+   * Starts debugging the test process, signalling the wait server, if any,
+   * once breakpoints have been set in the debugee.
+   */
+  protected attachDebugger(cp: ChildProcess, debugPort: number, server?: IWaitServer) {
     // Register a descriptor factory that signals the server when any
     // breakpoint set requests on the debugee have been completed.
-    const factory = vscode.debug.registerDebugAdapterTrackerFactory(DEBUG_TYPE, {
+    const factory = vscode.debug.registerDebugAdapterTrackerFactory(this.debugType, {
       createDebugAdapterTracker(session) {
         if (!session.parentSession || session.parentSession !== rootSession) {
           return;
@@ -64,7 +392,7 @@ export abstract class VSCodeTestRunner {
         return {
           onDidSendMessage(message) {
             if (message.type === 'response' && message.request_seq === breakpointRequestId) {
-              server.ready();
+              server?.ready();
             }
           },
           onWillReceiveMessage(message) {
@@ -73,7 +401,7 @@ export abstract class VSCodeTestRunner {
             }
 
             if (message.command === 'configurationDone') {
-              server.ready();
+              server?.ready();
             } else if (message.command === 'setBreakpoints') {
               breakpointRequestId = message.seq;
             }
@@ -82,13 +410,38 @@ export abstract class VSCodeTestRunner {
       },
     });
 
-    vscode.debug.startDebugging(this.repoLocation, ATTACH_CONFIG_NAME);
+    // a launch config of the same name in the workspace takes precedence,
+    // but has to attach on the port the process was started with
+    const builtInConfig = this.getAttachConfig(debugPort);
+    const attachName = builtInConfig.name;
+    const attachConfig = {
+      ...builtInConfig,
+      ...getLaunchConfig(this.repoLocation, attachName),
+      port: debugPort,
+    };
+    const onStartFailed = (reason: string) => {
+      if (!exited) {
+        vscode.window.showErrorMessage(`Could not attach the debugger to the tests: ${reason}`);
+        cp.kill();
+      }
+    };
+
+    vscode.debug
+      .startDebugging(this.repoLocation, attachConfig)
+      .then(
+        started => {
+          if (!started) {
+            onStartFailed(`the "${attachName}" configuration could not be started.`);
+          }
+        },
+        (err: Error) => onStartFailed(err.message)
+      );
 
     let exited = false;
     let rootSession: vscode.DebugSession | undefined;
     cp.once('exit', () => {
       exited = true;
-      server.dispose();
+      server?.dispose();
       listener.dispose();
       factory.dispose();
 
@@ -97,17 +450,71 @@ export abstract class VSCodeTestRunner {
       }
     });
 
+    // several test processes can be debugged at once under the same name,
+    // so the session is found by the port it attached to
     const listener = vscode.debug.onDidStartDebugSession(s => {
-      if (s.name === ATTACH_CONFIG_NAME && !rootSession) {
+      if (s.configuration.port === debugPort && !s.parentSession && !rootSession) {
         if (exited) {
-          vscode.debug.stopDebugging(rootSession);
+          vscode.debug.stopDebugging(s);
         } else {
           rootSession = s;
         }
       }
     });
+  }
 
-    return new TestOutputScanner(cp, args);
+  /**
+   * Type of the debug sessions started by the attach configuration.
+   */
+  protected readonly debugType: string = DEBUG_TYPE;
+
+  /**
+   * Adds arguments to start the test process for debugging on the port. The
+   * process should wait to run tests until it can connect to the `waitServerPort`.
+   */
+  protected prepareDebugArguments(
+    args: ReadonlyArray<string>,
+    waitServerPort: number,
+    debugPort: number
+  ) {
+    return [
+      ...args,
+      `--remote-debugging-port=${debugPort}`,
+      '--timeout=0',
+      `--waitServer=${waitServerPort}`,
+    ];
+  }
+
+  /**
+   * Gets how the browser launched by the test script should be debugged, if
+   * it runs tests in one.
+   */
+  protected getBrowserDebugConfig(
+    _waitServerPort: number,
+    _debugPort: number
+  ): IDebugBrowserConfig | undefined {
+    return undefined;
+  }
+
+  /**
+   * Gets the configuration used to attach to the test process on the port.
+   * A launch configuration of the same name in the workspace overrides it.
+   */
+  protected getAttachConfig(debugPort: number): vscode.DebugConfiguration {
+    const repo = this.repoLocation.uri.fsPath;
+    return {
+      type: this.debugType,
+      request: 'attach',
+      name: ATTACH_CONFIG_NAME,
+      port: debugPort,
+      timeout: 30000,
+      outFiles: [path.join(repo, 'out', '**', '*.js')],
+      sourceMapPathOverrides: {
+        'vscode-file://vscode-app/*': `${repo}/*`,
+        // file URIs on Windows start with the drive, as in `file:///c:/...`
+        'file:///*': process.platform === 'win32' ? '*' : '/*',
+      },
+    };
   }
 
   protected getEnvironment(): NodeJS.ProcessEnv {
@@ -115,40 +522,83 @@ export abstract class VSCodeTestRunner {
       ...process.env,
       ELECTRON_RUN_AS_NODE: undefined,
       ELECTRON_ENABLE_LOGGING: '1',
+      ...getDefaultEnv(this.repoLocation),
+      ...this.options.env,
     };
   }
 
+  /**
+   * Gets arguments for the test script from the settings and the profile's
+   * options. The timeout is left out when debugging, where tests don't time out.
+   */
+  protected getOptionArguments(debug: boolean) {
+    const { args, coverage, reporterOptions } = this.options;
+    const timeout = this.options.timeout ?? getDefaultTimeout(this.repoLocation);
+    return [
+      ...getDefaultArgs(this.repoLocation),
+      ...args,
+      ...(timeout !== undefined && !debug ? [`--timeout=${timeout}`] : []),
+      ...(coverage ? ['--coverage'] : []),
+      ...(reporterOptions.length ? ['--reporter-options', reporterOptions.join(',')] : []),
+    ];
+  }
+
+  /**
+   * Gets the arguments for the test script, and separately the arguments
+   * that select which tests it runs.
+   */
   private prepareArguments(
     baseArgs: ReadonlyArray<string>,
-    filter?: ReadonlyArray<vscode.TestItem>
+    filter: ReadonlyArray<vscode.TestItem> | undefined,
+    debug: boolean
   ) {
-    const args = [...this.getDefaultArgs(), ...baseArgs, '--reporter', 'full-json-stream'];
+    const args = [
+      ...this.getDefaultArgs(),
+      ...baseArgs,
+      ...this.getOptionArguments(debug),
+      '--reporter',
+      'full-json-stream',
+    ];
+    const selection: string[] = [];
     if (!filter) {
-      return args;
+      return { args, selection };
     }
 
-    const grepRe: string[] = [];
-    const runPaths: string[] = [];
-    for (const test of filter) {
-      const data = itemData.get(test);
-      if (data instanceof TestCase || data instanceof TestSuite) {
-        grepRe.push(escapeRe(data.fullName) + (data instanceof TestCase ? '$' : ' '));
-      } else if (data instanceof TestFile) {
-        runPaths.push(
-          path.relative(data.workspaceFolder.uri.fsPath, test.uri!.fsPath).replace(/\\/g, '/')
-        );
-      }
+    const { grep, files } = getTestSelection(filter);
+    if (grep) {
+      selection.push('--grep', `/${grep}/`);
     }
 
-    if (grepRe.length) {
-      args.push('--grep', `/^(${grepRe.join('|')})/`);
+    for (const file of files) {
+      selection.push('--run', this.getRunPath(file));
     }
 
-    if (runPaths.length) {
-      args.push(...runPaths.flatMap(p => ['--run', p]));
+    return { args, selection };
+  }
+
+  /**
+   * Starts the test script through the bootstrap module if there's a
+   * selection or browser to debug, which it reads from a file rather than
+   * the command line.
+   */
+  private async bootstrap(
+    args: string[],
+    config: IBootstrapConfig
+  ): Promise<{ args: string[]; remove: () => void; removeOnExit: (cp: ChildProcess) => void }> {
+    if (!config.args.length && !config.debugBrowser) {
+      return { args, remove: () => undefined, removeOnExit: () => undefined };
     }
 
-    return args;
+    const { file, remove, removeOnExit } = await writeTempJson('vscode-test-bootstrap', config);
+    return {
+      args: [path.join(__dirname, TEST_BOOTSTRAP_MODULE), file, ...args],
+      remove,
+      removeOnExit,
+    };
+  }
+
+  private getRunPath(uri: vscode.Uri) {
+    return path.relative(this.repoLocation.uri.fsPath, uri.fsPath).replace(/\\/g, '/');
   }
 
   protected abstract getDefaultArgs(): string[];
@@ -167,7 +617,7 @@ export abstract class VSCodeTestRunner {
     }
   }
 
-  private createWaitServer() {
+  protected createWaitServer(): IWaitServer {
     const onReady = new vscode.EventEmitter<void>();
     let ready = false;
 
@@ -195,6 +645,78 @@ export abstract class VSCodeTestRunner {
 }
 
 export class BrowserTestRunner extends VSCodeTestRunner {
+  public readonly environment = 'the browser';
+  protected readonly layers = BROWSER_LAYERS;
+
+  /** @override */
+  protected binaryPath(): Promise<string> {
+    return Promise.resolve(process.execPath);
+  }
+
+  /** @override */
+  protected getEnvironment() {
+    return {
+      ...super.getEnvironment(),
+      ELECTRON_RUN_AS_NODE: '1',
+    };
+  }
+
+  /**
+   * Only Chromium can be attached to, over the Chrome DevTools Protocol.
+   * @override
+   */
+  public async debug(baseArgs: ReadonlyArray<string>, filter?: ReadonlyArray<vscode.TestItem>) {
+    const browser = getBrowserType(baseArgs);
+    if (browser !== DEBUGGABLE_BROWSER) {
+      throw new Error(
+        `Debugging tests in ${browser ?? 'every browser'} isn't supported, ` +
+          `only ${DEBUGGABLE_BROWSER} can be attached to.`
+      );
+    }
+
+    return super.debug(baseArgs, filter);
+  }
+
+  /**
+   * Browsers are shown with the flag the debug profiles already pass.
+   * @override
+   */
+  protected getOptionArguments(debug: boolean) {
+    const args = super.getOptionArguments(debug);
+    return this.options.headed && !debug ? [...args, '--debug-browser'] : args;
+  }
+
+  /** @override */
+  protected getBrowserDebugConfig(waitServerPort: number, debugPort: number) {
+    return { port: debugPort, waitServerPort };
+  }
+
+  /**
+   * The browser, rather than the test script, listens for the debugger and
+   * waits for breakpoints, which the bootstrap module sets up.
+   * @override
+   */
+  protected prepareDebugArguments(args: ReadonlyArray<string>) {
+    return [...args, '--timeout=0'];
+  }
+
+  /** @override */
+  protected getAttachConfig(debugPort: number): vscode.DebugConfiguration {
+    return { ...super.getAttachConfig(debugPort), name: BROWSER_ATTACH_CONFIG_NAME };
+  }
+
+  /** @override */
+  protected getDefaultArgs() {
+    return [getTestScript(this.repoLocation, 'browser')];
+  }
+}
+
+export class NodeTestRunner extends VSCodeTestRunner {
+  public static readonly supportsCoverage = true;
+  public readonly environment = 'Node.js';
+  protected readonly layers = NODE_LAYERS;
+  protected readonly debugType = 'pwa-node';
+
   /** @override */
   protected binaryPath(): Promise<string> {
     return Promise.resolve(process.execPath);
@@ -210,33 +732,260 @@ export class BrowserTestRunner extends VSCodeTestRunner {
 
   /** @override */
   protected getDefaultArgs() {
-    return [TEST_BROWSER_SCRIPT_PATH];
+    return [getTestScript(this.repoLocation, 'node')];
+  }
+
+  /**
+   * Node.js waits in the inspector until the debugger attaches, so the wait
+   * server isn't needed for breakpoints to be set before tests start.
+   * @override
+   */
+  protected prepareDebugArguments(
+    args: ReadonlyArray<string>,
+    _waitServerPort: number,
+    debugPort: number
+  ) {
+    return [`--inspect-brk=${debugPort}`, ...args, '--timeout=0'];
+  }
+
+  /** @override */
+  protected getAttachConfig(debugPort: number): vscode.DebugConfiguration {
+    return {
+      type: this.debugType,
+      request: 'attach',
+      name: NODE_ATTACH_CONFIG_NAME,
+      port: debugPort,
+      continueOnAttach: true,
+      outFiles: [path.join(this.repoLocation.uri.fsPath, 'out', '**', '*.js')],
+    };
   }
 }
 
-export class WindowsTestRunner extends VSCodeTestRunner {
+export class ExtensionHostTestRunner extends VSCodeTestRunner {
+  public readonly environment = 'the extension host';
+  protected readonly layers: ReadonlySet<string> = new Set([EXTENSION_HOST_LAYER]);
+  protected readonly debugType = 'pwa-node';
+
+  /**
+   * Each extension's tests run in a separate process with it as the
+   * extension under development, split further by selection scope.
+   * @override
+   */
+  public groupByProcess(tests: ReadonlyArray<vscode.TestItem>) {
+    const groups = new Map<string, vscode.TestItem[]>();
+    for (const test of tests) {
+      const items = itemData.get(test) instanceof TestFolder ? getContainedTestFiles(test) : [test];
+      for (const item of items) {
+        const name = getExtensionName(item.uri!);
+        if (name) {
+          groups.set(name, [...(groups.get(name) ?? []), item]);
+        }
+      }
+    }
+
+    return [...groups.values()].flatMap(splitBySelectionScope);
+  }
+
   /** @override */
-  protected async binaryPath() {
-    const { nameShort } = await this.readProductJson();
-    return path.join(this.repoLocation.uri.fsPath, `.build/electron/${nameShort}.exe`);
+  public run(baseArgs: ReadonlyArray<string>, filter?: ReadonlyArray<vscode.TestItem>) {
+    return this.start(baseArgs, filter, false);
+  }
+
+  /** @override */
+  public debug(baseArgs: ReadonlyArray<string>, filter?: ReadonlyArray<vscode.TestItem>) {
+    return this.start(baseArgs, filter, true);
+  }
+
+  /**
+   * Starts VS Code the way `scripts/test-integration` does, with a test
+   * module from this extension that runs the selected files and reports
+   * results back over a socket. Arguments from the profile's options are
+   * passed to VS Code, and the configured timeout to mocha.
+   */
+  private async start(
+    baseArgs: ReadonlyArray<string>,
+    filter: ReadonlyArray<vscode.TestItem> | undefined,
+    debug: boolean
+  ) {
+    const name = filter?.map(t => t.uri && getExtensionName(t.uri)).find(n => !!n);
+    if (!filter || !name) {
+      throw new Error('Extension host tests must be run for a single extension');
+    }
+
+    const repo = this.repoLocation.uri.fsPath;
+    const extensionPath = path.join(repo, 'extensions', name);
+    const testWorkspace = path.join(extensionPath, 'testWorkspace');
+    const { grep, files } = getTestSelection(filter);
+
+    const results = createServer();
+    await new Promise<void>(resolve => results.listen(0, '127.0.0.1', resolve));
+
+    const config: IExtensionTestConfig = {
+      port: (results.address() as AddressInfo).port,
+      repo,
+      grep,
+      files: files.map(f => getCompiledPathForSourceFile(f.fsPath)),
+      timeout: debug ? 0 : this.options.timeout ?? getDefaultTimeout(this.repoLocation),
+    };
+    const configFile = await writeTempJson('vscode-test-config', config);
+    const userDataDir = await fs.mkdtemp(path.join(tmpdir(), 'vscode-tests-'));
+    let cleanedUp = false;
+    const cleanUp = () => {
+      if (cleanedUp) {
+        return;
+      }
+      cleanedUp = true;
+      results.close();
+      configFile.remove();
+      fs.rmdir(userDataDir, { recursive: true }).catch(() => undefined);
+    };
+
+    const args = [
+      ...((await exists(testWorkspace)) ? [testWorkspace] : []),
+      `--extensionDevelopmentPath=${extensionPath}`,
+      `--extensionTestsPath=${path.join(__dirname, EXTENSION_TESTS_MODULE)}`,
+      `--enable-proposed-api=vscode.${name}`,
+      `--user-data-dir=${userDataDir}`,
+      ...this.getDefaultArgs(),
+      ...baseArgs,
+      ...this.options.args,
+    ];
+
+    const debugPort = debug ? await getFreePort() : undefined;
+    if (debugPort) {
+      args.push(`--inspect-brk-extensions=${debugPort}`);
+    }
+
+    const cp = await this.spawnTestProcess(args, {
+      ...this.getEnvironment(),
+      [EXTENSION_TESTS_CONFIG_VAR]: configFile.file,
+    }).catch(e => {
+      cleanUp();
+      throw e;
+    });
+    // a process that fails to start errors without exiting
+    cp.once('exit', cleanUp);
+    cp.once('error', cleanUp);
+
+    const scanner = new TestOutputScanner(cp, args);
+    results.on('connection', socket => scanner.readFrom(socket));
+
+    // the extension host waits for the debugger itself, so there's no wait server
+    if (debugPort) {
+      this.attachDebugger(cp, debugPort);
+    }
+
+    return scanner;
+  }
+
+  /**
+   * `code.bat` can only be started through a shell on Windows.
+   * @override
+   */
+  protected async spawnTestProcess(args: ReadonlyArray<string>, env = this.getEnvironment()) {
+    return spawn(await this.binaryPath(), args, {
+      cwd: this.repoLocation.uri.fsPath,
+      stdio: 'pipe',
+      env,
+      shell: process.platform === 'win32',
+    });
+  }
+
+  /** @override */
+  protected binaryPath() {
+    return Promise.resolve(
+      path.join(
+        this.repoLocation.uri.fsPath,
+        'scripts',
+        process.platform === 'win32' ? 'code.bat' : 'code.sh'
+      )
+    );
   }
 
   /** @override */
   protected getDefaultArgs() {
-    return [TEST_ELECTRON_SCRIPT_PATH];
+    return [
+      '--disable-extensions',
+      '--disable-telemetry',
+      '--disable-updates',
+      '--disable-workspace-trust',
+      '--no-cached-data',
+      '--skip-welcome',
+      '--skip-release-notes',
+    ];
+  }
+
+  /** @override */
+  protected getAttachConfig(debugPort: number): vscode.DebugConfiguration {
+    return {
+      type: this.debugType,
+      request: 'attach',
+      name: EXTENSION_HOST_ATTACH_CONFIG_NAME,
+      port: debugPort,
+      continueOnAttach: true,
+      outFiles: [path.join(this.repoLocation.uri.fsPath, 'extensions', '*', 'out', '**', '*.js')],
+    };
   }
 }
 
-export class PosixTestRunner extends VSCodeTestRunner {
+/**
+ * Runs tests in the Electron renderer, with the Electron the repo downloads.
+ */
+abstract class ElectronTestRunner extends VSCodeTestRunner {
+  public static readonly supportsCoverage = true;
+  public readonly environment = 'Electron';
+  protected readonly layers = ELECTRON_LAYERS;
+
   /** @override */
-  protected async binaryPath() {
-    const { applicationName } = await this.readProductJson();
-    return path.join(this.repoLocation.uri.fsPath, `.build/electron/${applicationName}`);
+  protected async preflight() {
+    await verifyElectronBinary(this.repoLocation, await this.binaryPath());
   }
 
   /** @override */
   protected getDefaultArgs() {
-    return [TEST_ELECTRON_SCRIPT_PATH];
+    return [getTestScript(this.repoLocation, 'electron')];
+  }
+}
+
+export class WindowsTestRunner extends ElectronTestRunner {
+  /** @override */
+  protected async binaryPath() {
+    const { nameShort } = await this.readProductJson();
+    return path.join(this.repoLocation.uri.fsPath, `.build/electron/${nameShort}.exe`);
+  }
+}
+
+export class PosixTestRunner extends ElectronTestRunner {
+  /**
+   * Runs tests on a new Xvfb display when there's no display, such as on a
+   * headless Linux machine. The display is shut down with the test process.
+   * @override
+   */
+  protected async spawnTestProcess(args: ReadonlyArray<string>, env = this.getEnvironment()) {
+    if (!needsVirtualDisplay(env)) {
+      return super.spawnTestProcess(args, env);
+    }
+
+    const { display, dispose } = await startVirtualDisplay();
+    try {
+      const cp = await super.spawnTestProcess([...args, ...HEADLESS_ELECTRON_ARGS], {
+        ...env,
+        DISPLAY: display,
+      });
+      // a process that can't be started only emits an error
+      cp.once('exit', dispose);
+      cp.once('error', dispose);
+      return cp;
+    } catch (e) {
+      dispose();
+      throw e;
+    }
+  }
+
+  /** @override */
+  protected async binaryPath() {
+    const { applicationName } = await this.readProductJson();
+    return path.join(this.repoLocation.uri.fsPath, `.build/electron/${applicationName}`);
   }
 }
 
@@ -244,7 +993,7 @@ export class DarwinTestRunner extends PosixTestRunner {
   /** @override */
   protected getDefaultArgs() {
     return [
-      TEST_ELECTRON_SCRIPT_PATH,
+      getTestScript(this.repoLocation, 'electron'),
       '--no-sandbox',
       '--disable-dev-shm-usage',
       '--use-gl=swiftshader',